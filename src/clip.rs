@@ -0,0 +1,91 @@
+use crate::{Pattern, Point, Region};
+
+impl Pattern {
+    /// The parts of every region inside the window from `lo` to `hi`, dropping regions
+    /// that fall entirely outside it
+    ///
+    /// Each region is clipped against the window's four edges in turn. A concave region
+    /// cut into several pieces stays one region, joined by zero-width runs along the
+    /// window edge.
+    pub fn cropped(&self, (lo, hi): (Point, Point)) -> Pattern {
+        self.0
+            .iter()
+            .filter_map(|region| {
+                let mut pts = region.points.clone();
+                if pts.len() > 1 && pts.first() == pts.last() {
+                    pts.pop();
+                }
+                for edge in [
+                    Edge::Left(lo.x),
+                    Edge::Right(hi.x),
+                    Edge::Bottom(lo.y),
+                    Edge::Top(hi.y),
+                ] {
+                    pts = clip(&pts, edge);
+                }
+                pts.dedup();
+                if pts.len() > 1 && pts.first() == pts.last() {
+                    pts.pop();
+                }
+                let first = *pts.first()?;
+                pts.push(first);
+                let clipped = pts.into_iter().collect::<Region>();
+                (clipped.signed_area() != 0.).then(|| clipped.with_attributes_of(region))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Edge {
+    Left(i32),
+    Right(i32),
+    Bottom(i32),
+    Top(i32),
+}
+
+impl Edge {
+    fn inside(self, p: Point) -> bool {
+        match self {
+            Edge::Left(x) => p.x >= x,
+            Edge::Right(x) => p.x <= x,
+            Edge::Bottom(y) => p.y >= y,
+            Edge::Top(y) => p.y <= y,
+        }
+    }
+    /// Where segment `a`-`b`, which crosses the edge, meets it
+    fn crossing(self, a: Point, b: Point) -> Point {
+        let along = |a0: i32, a1: i32, b0: i32, b1: i32, at: i32| {
+            let t = (at - a0) as f64 / (b0 - a0) as f64;
+            (a1 as f64 + (b1 - a1) as f64 * t).round() as i32
+        };
+        match self {
+            Edge::Left(x) | Edge::Right(x) => Point {
+                x,
+                y: along(a.x, a.y, b.x, b.y, x),
+            },
+            Edge::Bottom(y) | Edge::Top(y) => Point {
+                x: along(a.y, a.x, b.y, b.x, y),
+                y,
+            },
+        }
+    }
+}
+
+/// One Sutherland-Hodgman pass keeping the part of the open ring `pts` inside `edge`
+fn clip(pts: &[Point], edge: Edge) -> Vec<Point> {
+    let mut out = vec![];
+    for (i, &b) in pts.iter().enumerate() {
+        let a = pts[(i + pts.len() - 1) % pts.len()];
+        match (edge.inside(a), edge.inside(b)) {
+            (true, true) => out.push(b),
+            (true, false) => out.push(edge.crossing(a, b)),
+            (false, true) => {
+                out.push(edge.crossing(a, b));
+                out.push(b);
+            }
+            (false, false) => {}
+        }
+    }
+    out
+}
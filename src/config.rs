@@ -0,0 +1,128 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Project settings loaded with `--config`, keyed like the long command line options
+///
+/// Every field is optional; anything given on the command line takes precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub cell: Option<String>,
+    pub layers: Option<LayerList>,
+    pub datatype: Option<Vec<i16>>,
+    pub datatype_range: Option<Vec<String>>,
+    pub strict: Option<bool>,
+    pub expect_layers: Option<bool>,
+    pub local_origin: Option<String>,
+    pub center: Option<bool>,
+    pub origin_from_layer: Option<i16>,
+    pub origin_first: Option<bool>,
+    pub crop: Option<String>,
+    pub crop_from_layer: Option<i16>,
+    pub panel: Option<String>,
+    pub optimize_travel: Option<bool>,
+    pub keep_largest: Option<usize>,
+    pub facets: Option<u32>,
+    pub format: Option<String>,
+    pub layer_offset: Option<i32>,
+    pub output: Option<String>,
+    pub coord_format: Option<String>,
+    pub placeholder_depth: Option<u32>,
+    pub bias: Option<f64>,
+    pub bias_join: Option<String>,
+    pub mask_expand: Option<f64>,
+    pub exclude_cell: Option<Vec<String>>,
+    pub add_bbox_outline: Option<bool>,
+    pub holes: Option<bool>,
+    pub flash_rects: Option<bool>,
+    pub invert: Option<bool>,
+    pub image_polarity: Option<String>,
+    pub board_outline: Option<bool>,
+    pub allow_missing_refs: Option<bool>,
+    pub outline_width: Option<f64>,
+    pub gerber_style: Option<String>,
+    pub svg_all: Option<PathBuf>,
+    pub max_file_bytes: Option<usize>,
+    pub jobs: Option<u32>,
+    pub aperture_report: Option<PathBuf>,
+    pub report_user_units: Option<bool>,
+    pub csv: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub summary: Option<PathBuf>,
+    pub stream: Option<bool>,
+    pub verbose: Option<bool>,
+    pub quiet: Option<bool>,
+}
+
+/// Layers to convert, as a plain list or as a table from layer number to either the label
+/// that replaces the number in `{layer}` of output names or a `{ label, color }` table
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LayerList {
+    Numbers(Vec<i16>),
+    Labelled(BTreeMap<String, LayerEntry>),
+}
+
+/// One layer of a labelled layer table
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LayerEntry {
+    Label(String),
+    Styled(LayerStyle),
+}
+
+/// How a layer is named in output names and drawn in the SVG overview
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerStyle {
+    pub label: Option<String>,
+    /// Any SVG color, replacing the layer's generated fill in `--svg-all`
+    pub color: Option<String>,
+}
+
+impl LayerList {
+    /// Layer numbers in order, each with whatever label and color was given
+    pub fn resolve(self) -> Result<Vec<(i16, LayerStyle)>> {
+        match self {
+            LayerList::Numbers(layers) => Ok(layers
+                .into_iter()
+                .map(|l| (l, LayerStyle::default()))
+                .collect()),
+            LayerList::Labelled(map) => {
+                let mut layers = map
+                    .into_iter()
+                    .map(|(layer, entry)| {
+                        let layer = layer
+                            .parse()
+                            .with_context(|| format!("Invalid layer number `{layer}` in config"))?;
+                        let style = match entry {
+                            LayerEntry::Label(label) => LayerStyle {
+                                label: Some(label),
+                                color: None,
+                            },
+                            LayerEntry::Styled(style) => style,
+                        };
+                        Ok((layer, style))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                // Table keys come back in string order, so 10 would sort before 2
+                layers.sort_by_key(|&(layer, _)| layer);
+                Ok(layers)
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config {}", path.display()))
+    }
+}
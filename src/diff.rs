@@ -0,0 +1,141 @@
+use itertools::Itertools;
+
+use crate::{bounds, Pattern, Point, Region};
+
+/// How two flattened patterns of the same layer differ
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatternDiff {
+    /// Regions only in the second pattern
+    pub added: usize,
+    /// Regions only in the first pattern
+    pub removed: usize,
+    /// Regions moved or reshaped, paired up because their extents overlap
+    pub changed: usize,
+    /// Extent of every unmatched region on either side
+    pub bbox: Option<(Point, Point)>,
+}
+
+impl PatternDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.changed == 0
+    }
+}
+
+impl Pattern {
+    /// Compares the regions of two patterns, ignoring their order, start vertex and any
+    /// vertex moved by at most `tolerance` database units
+    ///
+    /// Regions without an equal counterpart are paired as changed when their bounding boxes
+    /// overlap, and otherwise count as removed from `self` or added in `other`.
+    pub fn diff(&self, other: &Pattern, tolerance: i32) -> PatternDiff {
+        let old = self.0.iter().map(ring).collect_vec();
+        let new = other.0.iter().map(ring).collect_vec();
+        let mut new_matched = vec![false; new.len()];
+        let mut removed = vec![];
+        for a in &old {
+            let found =
+                (0..new.len()).find(|&j| !new_matched[j] && same_ring(a, &new[j], tolerance));
+            match found {
+                Some(j) => new_matched[j] = true,
+                None => removed.push(a),
+            }
+        }
+        let mut added = (0..new.len())
+            .filter(|&j| !new_matched[j])
+            .map(|j| &new[j])
+            .collect_vec();
+        let bbox = bounds(removed.iter().chain(&added).flat_map(|r| r.iter().copied()));
+
+        let mut changed = 0;
+        removed.retain(|a| {
+            let lo_hi = bounds(a.iter().copied());
+            let pair = added
+                .iter()
+                .position(|b| overlaps(lo_hi, bounds(b.iter().copied())));
+            match pair {
+                Some(j) => {
+                    added.swap_remove(j);
+                    changed += 1;
+                    false
+                }
+                None => true,
+            }
+        });
+        PatternDiff {
+            added: added.len(),
+            removed: removed.len(),
+            changed,
+            bbox,
+        }
+    }
+}
+
+/// Vertices of the region without the repeated closing point
+fn ring(region: &Region) -> Vec<Point> {
+    let mut pts = region.points.clone();
+    if pts.len() > 1 && pts.first() == pts.last() {
+        pts.pop();
+    }
+    pts
+}
+
+/// Whether the rings visit the same vertices in the same direction, from any start
+fn same_ring(a: &[Point], b: &[Point], tolerance: i32) -> bool {
+    let near = |p: &Point, q: &Point| {
+        (p.x as i64 - q.x as i64).abs() <= tolerance as i64
+            && (p.y as i64 - q.y as i64).abs() <= tolerance as i64
+    };
+    a.len() == b.len()
+        && (a.is_empty()
+            || (0..b.len()).any(|k| {
+                a.iter()
+                    .zip(b.iter().cycle().skip(k))
+                    .all(|(p, q)| near(p, q))
+            }))
+}
+
+fn overlaps(a: Option<(Point, Point)>, b: Option<(Point, Point)>) -> bool {
+    match (a, b) {
+        (Some((alo, ahi)), Some((blo, bhi))) => {
+            alo.x <= bhi.x && blo.x <= ahi.x && alo.y <= bhi.y && blo.y <= ahi.y
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+
+    #[test]
+    fn counts_added_removed_and_changed_regions() {
+        let lib = library([
+            cell(
+                "OLD",
+                [
+                    rect(1, (0, 0), (10, 10)),
+                    rect(1, (100, 0), (110, 10)),
+                    rect(1, (200, 0), (210, 10)),
+                ],
+            ),
+            cell(
+                "NEW",
+                [
+                    boundary(1, 0, &[(10, 10), (0, 10), (0, 0), (10, 0)]),
+                    rect(1, (102, 0), (112, 10)),
+                    rect(1, (500, 500), (510, 510)),
+                    rect(1, (600, 500), (610, 510)),
+                ],
+            ),
+        ]);
+        let (old, new) = (convert(&lib, "OLD", 1), convert(&lib, "NEW", 1));
+        let diff = old.diff(&new, 0);
+        assert_eq!((diff.added, diff.removed, diff.changed), (2, 1, 1));
+        let corners = diff.bbox.map(|(lo, hi)| ((lo.x, lo.y), (hi.x, hi.y)));
+        assert_eq!(corners, Some(((100, 0), (610, 510))));
+        // Within tolerance the shifted square matches again
+        let diff = old.diff(&new, 2);
+        assert_eq!((diff.added, diff.removed, diff.changed), (2, 1, 0));
+        assert!(old.diff(&old, 0).is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+//! In-memory libraries for the unit tests
+
+use gds21::{
+    GdsArrayRef, GdsBoundary, GdsElement, GdsLibrary, GdsPath, GdsPoint, GdsStrans, GdsStruct,
+    GdsStructRef,
+};
+
+use crate::{ConvertOptions, GerberOptions, Pattern, Region};
+
+/// Library of the given cells, in the default units of 1 nm per database unit
+pub(crate) fn library(cells: impl IntoIterator<Item = GdsStruct>) -> GdsLibrary {
+    let mut lib = GdsLibrary::new("test");
+    lib.structs.extend(cells);
+    lib
+}
+
+pub(crate) fn cell(name: &str, elems: impl IntoIterator<Item = GdsElement>) -> GdsStruct {
+    let mut struc = GdsStruct::new(name);
+    struc.elems.extend(elems);
+    struc
+}
+
+/// Boundary through the points, closed by repeating the first one
+pub(crate) fn boundary(layer: i16, datatype: i16, pts: &[(i32, i32)]) -> GdsElement {
+    let mut xy = GdsPoint::vec(pts);
+    xy.push(xy[0].clone());
+    GdsBoundary {
+        layer,
+        datatype,
+        xy,
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Counter-clockwise axis-aligned rectangle boundary from `lo` to `hi`
+pub(crate) fn rect(layer: i16, lo: (i32, i32), hi: (i32, i32)) -> GdsElement {
+    boundary(layer, 0, &[lo, (hi.0, lo.1), hi, (lo.0, hi.1)])
+}
+
+pub(crate) fn path(layer: i16, path_type: i16, width: i32, pts: &[(i32, i32)]) -> GdsElement {
+    GdsPath {
+        layer,
+        xy: GdsPoint::vec(pts),
+        width: Some(width),
+        path_type: Some(path_type),
+        ..Default::default()
+    }
+    .into()
+}
+
+pub(crate) fn sref(name: &str, at: (i32, i32), strans: Option<GdsStrans>) -> GdsElement {
+    GdsStructRef {
+        name: name.into(),
+        xy: GdsPoint::new(at.0, at.1),
+        strans,
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Array reference from its origin, column end and row end corners as GDS stores them
+pub(crate) fn aref(
+    name: &str,
+    (cols, rows): (i16, i16),
+    corners: [(i32, i32); 3],
+    strans: Option<GdsStrans>,
+) -> GdsElement {
+    GdsArrayRef {
+        name: name.into(),
+        xy: corners.map(|(x, y)| GdsPoint::new(x, y)),
+        cols,
+        rows,
+        strans,
+        ..Default::default()
+    }
+    .into()
+}
+
+pub(crate) fn rotated(angle: f64) -> Option<GdsStrans> {
+    Some(GdsStrans {
+        angle: Some(angle),
+        ..Default::default()
+    })
+}
+
+/// Flattens one layer of a cell with default options
+pub(crate) fn convert(lib: &GdsLibrary, cell: &str, layer: i16) -> Pattern {
+    Pattern::from_gds_struct(lib, cell, layer, &ConvertOptions::default()).unwrap()
+}
+
+/// Vertices of the region in database units, closing point included
+pub(crate) fn coords(region: &Region) -> Vec<(i32, i32)> {
+    region.points().iter().map(|p| (p.x, p.y)).collect()
+}
+
+/// Vertices of every region, sorted so that emission order does not matter
+pub(crate) fn sorted_coords(pattern: &Pattern) -> Vec<Vec<(i32, i32)>> {
+    let mut regions = pattern.regions().map(coords).collect::<Vec<_>>();
+    regions.sort();
+    regions
+}
+
+pub(crate) fn gerber(pattern: &Pattern, lib: &GdsLibrary, opts: &GerberOptions) -> String {
+    let mut out = vec![];
+    pattern.write_gerber(&mut out, lib, opts).unwrap();
+    String::from_utf8(out).unwrap()
+}
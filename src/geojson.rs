@@ -0,0 +1,104 @@
+use std::io::{self, Write};
+
+use gds21::GdsLibrary;
+use itertools::Itertools;
+use serde_json::{json, Value};
+
+use crate::{Pattern, Region};
+
+impl Pattern {
+    /// Writes the pattern as a GeoJSON FeatureCollection with one Polygon per region, in
+    /// millimeters, carrying the layer and datatype as properties
+    ///
+    /// With `holes`, regions at an odd nesting depth become interior rings of the region
+    /// directly around them instead of features of their own.
+    pub fn write_geojson(
+        &self,
+        w: &mut impl Write,
+        lib: &GdsLibrary,
+        layer: Option<i16>,
+        holes: bool,
+    ) -> io::Result<()> {
+        let enclosing = if holes {
+            self.enclosing()
+        } else {
+            vec![vec![]; self.0.len()]
+        };
+        let mut rings = vec![vec![]; self.0.len()];
+        for (i, outer) in enclosing.iter().enumerate() {
+            if outer.len() % 2 == 1 {
+                let area = |j: &&usize| self.0[**j].signed_area().abs();
+                let parent = outer.iter().min_by(|a, b| area(a).total_cmp(&area(b)));
+                rings[*parent.unwrap()].push(i);
+            }
+        }
+        let features = enclosing
+            .iter()
+            .enumerate()
+            .filter(|(_, outer)| outer.len() % 2 == 0)
+            .map(|(i, _)| {
+                let region = &self.0[i];
+                let coords = std::iter::once(ring(region, lib, true))
+                    .chain(rings[i].iter().map(|&h| ring(&self.0[h], lib, false)))
+                    .collect_vec();
+                json!({
+                    "type": "Feature",
+                    "properties": { "layer": layer, "datatype": region.datatype() },
+                    "geometry": { "type": "Polygon", "coordinates": coords },
+                })
+            })
+            .collect_vec();
+        let doc = json!({ "type": "FeatureCollection", "features": features });
+        serde_json::to_writer(&mut *w, &doc)?;
+        writeln!(w)
+    }
+}
+
+/// Closed ring of a region's vertices in millimeters, counter-clockwise for exteriors and
+/// clockwise for holes as GeoJSON's right-hand rule asks
+fn ring(region: &Region, lib: &GdsLibrary, exterior: bool) -> Value {
+    let mut pts = region.to_mm_points(lib);
+    if (region.signed_area() > 0.) != exterior {
+        pts.reverse();
+    }
+    if pts.first() != pts.last() {
+        pts.push(pts[0]);
+    }
+    json!(pts.into_iter().map(|(x, y)| [x, y]).collect_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn collection_has_one_feature_per_region_in_clean_millimeters() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (3, 7)),
+                rect(1, (123, 0), (999, 15)),
+                boundary(1, 2, &[(0, 100), (10, 100), (0, 110)]),
+            ],
+        )]);
+        let mut out = vec![];
+        convert(&lib, "TOP", 1)
+            .write_geojson(&mut out, &lib, Some(1), false)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let doc: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(doc["type"], "FeatureCollection");
+        let features = doc["features"].as_array().unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(
+            features[2]["properties"],
+            json!({ "layer": 1, "datatype": 2 })
+        );
+        assert_eq!(
+            features[0]["geometry"]["coordinates"],
+            json!([[[0., 0.], [3e-6, 0.], [3e-6, 7e-6], [0., 7e-6], [0., 0.]]])
+        );
+        assert!(text.contains("[0.000999,0.000015]"));
+    }
+}
@@ -0,0 +1,2778 @@
+//! Flattening of GDSII cell hierarchies into Gerber regions
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    io::Write,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use gds21::{
+    GdsArrayRef, GdsElement, GdsLibrary, GdsPath, GdsPoint, GdsProperty, GdsStrans, GdsStruct,
+    GdsStructRef,
+};
+use gerber_types::{CoordinateFormat, CoordinateNumber, GerberError};
+use itertools::Itertools;
+use serde_json::json;
+use thiserror::Error;
+
+mod clip;
+mod diff;
+#[cfg(test)]
+mod fixtures;
+mod geojson;
+mod offset;
+mod stream;
+mod svg;
+mod union;
+mod validate;
+
+pub use diff::PatternDiff;
+pub use offset::Join;
+pub use stream::load_subtree;
+pub use svg::write_svg_stack;
+pub use validate::{validate, Problem};
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// How much the conversion reports on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+pub fn set_verbosity(level: Verbosity) {
+    VERBOSITY.store(level as u8, Ordering::Relaxed);
+}
+#[doc(hidden)]
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+thread_local! {
+    /// Lines held back from stderr by [`capture_stderr`] on this thread
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+#[doc(hidden)]
+pub fn emit(line: fmt::Arguments) {
+    CAPTURED.with_borrow_mut(|captured| match captured {
+        Some(lines) => lines.push(line.to_string()),
+        None => eprintln!("{line}"),
+    })
+}
+/// Runs `f`, collecting the lines [`warn!`] and [`info!`] print on this thread instead of
+/// writing them to stderr
+#[doc(hidden)]
+pub fn capture_stderr<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    let outer = CAPTURED.replace(Some(vec![]));
+    let value = f();
+    let lines = CAPTURED.replace(outer).unwrap_or_default();
+    (value, lines)
+}
+
+/// Prints a warning to stderr unless `--quiet` was given
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::verbosity() >= $crate::Verbosity::Normal as u8 {
+            $crate::emit(format_args!("warning: {}", format_args!($($arg)*)));
+        }
+    };
+}
+/// Prints progress to stderr only when `--verbose` was given
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::verbosity() >= $crate::Verbosity::Verbose as u8 {
+            $crate::emit(format_args!($($arg)*));
+        }
+    };
+}
+
+/// Settings that control how cell geometry is resolved into regions
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Facets per full circle for polygonized curves
+    pub facets: u32,
+    /// Hierarchy depth below which referenced cells are replaced by their bounding box
+    pub placeholder_depth: Option<u32>,
+    /// Referenced cells that are skipped entirely instead of being flattened
+    pub exclude_cells: Vec<String>,
+    /// Skip references to cells missing from the library instead of failing
+    pub allow_missing_refs: bool,
+    /// Datatypes to convert, every datatype when empty
+    pub datatypes: Vec<RangeInclusive<i16>>,
+}
+
+impl ConvertOptions {
+    fn keeps(&self, datatype: i16) -> bool {
+        self.datatypes.is_empty() || self.datatypes.iter().any(|r| r.contains(&datatype))
+    }
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            facets: 32,
+            placeholder_depth: None,
+            exclude_cells: vec![],
+            allow_missing_refs: false,
+            datatypes: vec![],
+        }
+    }
+}
+
+/// Converts one layer of a cell in an in-memory GDS stream to Gerber with default options,
+/// for callers that have no filesystem to load from
+pub fn convert_bytes(gds: &[u8], cell: &str, layer: i16) -> PatternResult<Vec<u8>> {
+    let lib = GdsLibrary::from_bytes(gds.to_vec())
+        .map_err(|e| PatternError::InvalidGds(e.to_string()))?;
+    let pat = Pattern::from_gds_struct(&lib, cell, layer, &ConvertOptions::default())?;
+    let mut out = vec![];
+    pat.write_gerber(&mut out, &lib, &GerberOptions::default())?;
+    Ok(out)
+}
+
+/// Flattened geometry of one layer of a cell
+#[derive(Debug, Clone)]
+pub struct Pattern(Vec<Region>);
+
+impl Pattern {
+    pub fn from_gds_struct(
+        lib: &GdsLibrary,
+        name: &str,
+        layer: i16,
+        opts: &ConvertOptions,
+    ) -> PatternResult<Self> {
+        let flat = !find_struct(lib, name)?
+            .elems
+            .iter()
+            .any(|e| matches!(e, GdsElement::GdsStructRef(_) | GdsElement::GdsArrayRef(_)));
+        // A cell without references never consults the per-cell layer index, so batches
+        // of small flat cells skip building it over the whole library
+        let index = if flat {
+            CellIndex::default()
+        } else {
+            CellIndex::new(lib)
+        };
+        Self::from_indexed(lib, &index, name, layer, opts)
+    }
+    /// Same as [`Pattern::from_gds_struct`], reusing an index built once for `lib` so that
+    /// converting several layers does not walk the whole library each time
+    pub fn from_indexed(
+        lib: &GdsLibrary,
+        index: &CellIndex,
+        name: &str,
+        layer: i16,
+        opts: &ConvertOptions,
+    ) -> PatternResult<Self> {
+        Ok(Self::counting_visits(lib, index, name, layer, opts)?.0)
+    }
+    /// Same as [`Pattern::from_indexed`], also returning how many cell placements were
+    /// resolved, to show what the index pruned
+    pub(crate) fn counting_visits(
+        lib: &GdsLibrary,
+        index: &CellIndex,
+        name: &str,
+        layer: i16,
+        opts: &ConvertOptions,
+    ) -> PatternResult<(Self, usize)> {
+        check_units(lib)?;
+        let mut walk = Walk::new(index);
+        let (pat, rounding) = Self::resolve(lib, name, layer, opts, 0, &mut walk)?;
+        info!(
+            "Flattened layer {layer} of cell {name} from {} cell visits",
+            walk.visits
+        );
+        if rounding > ROUNDING_WARN_DB {
+            warn!(
+                "Transforms in cell {name} moved layer {layer} coordinates off the database grid, rounding them by up to {rounding:.3} database units"
+            );
+        }
+        Ok((pat, walk.visits))
+    }
+    /// Flattens the cell, also returning a bound on how far rounding transformed
+    /// coordinates back onto the database grid moved any point, in database units
+    fn resolve(
+        lib: &GdsLibrary,
+        name: &str,
+        layer: i16,
+        opts: &ConvertOptions,
+        depth: u32,
+        walk: &mut Walk,
+    ) -> PatternResult<(Self, f64)> {
+        let struc = find_struct(lib, name)?;
+        if opts.placeholder_depth.is_some_and(|n| depth > n) {
+            let placeholder = Self::extent(lib, name, layer, opts, walk)?;
+            return Ok((placeholder.map(Region::rect).into_iter().collect(), 0.));
+        }
+        walk.enter(name)?;
+        let mut regions: Vec<Region> = vec![];
+        let mut rounding: f64 = 0.;
+        for elem in &struc.elems {
+            match elem {
+                GdsElement::GdsBoundary(b) if b.layer == layer && opts.keeps(b.datatype) => {
+                    let region = b.xy.iter().collect::<Region>();
+                    regions.push(
+                        region
+                            .with_datatype(b.datatype)
+                            .with_properties(&b.properties),
+                    )
+                }
+                GdsElement::GdsBoundary(_) => {}
+                GdsElement::GdsPath(p) if p.layer == layer && opts.keeps(p.datatype) => {
+                    let region = Region::from_path(p, opts.facets);
+                    regions.extend(
+                        region.map(|r| r.with_datatype(p.datatype).with_properties(&p.properties)),
+                    )
+                }
+                GdsElement::GdsPath(_) => {}
+                GdsElement::GdsBox(b) if b.layer == layer && opts.keeps(b.boxtype) => {
+                    let region = b.xy.iter().collect::<Region>();
+                    regions.push(
+                        region
+                            .with_datatype(b.boxtype)
+                            .with_properties(&b.properties),
+                    )
+                }
+                GdsElement::GdsBox(_) => {}
+                GdsElement::GdsStructRef(r) if walk.skips(lib, opts, &r.name, layer) => {}
+                GdsElement::GdsArrayRef(r) if walk.skips(lib, opts, &r.name, layer) => {}
+                GdsElement::GdsStructRef(GdsStructRef {
+                    name, xy, strans, ..
+                }) => {
+                    let t = Transform::from_strans(strans.as_ref());
+                    let (pat, inner) = Pattern::resolve(lib, name, layer, opts, depth + 1, walk)?;
+                    let (pat, err) = pat.transformed(&t);
+                    rounding = rounding.max(inner * t.scale() + err);
+                    regions.extend((pat + xy.into()).0);
+                }
+                GdsElement::GdsArrayRef(aref) => {
+                    let t = Transform::from_strans(aref.strans.as_ref());
+                    let (pat, inner) =
+                        Pattern::resolve(lib, &aref.name, layer, opts, depth + 1, walk)?;
+                    let (pat, err) = pat.transformed(&t);
+                    rounding = rounding.max(inner * t.scale() + err);
+                    for offset in array_offsets(aref) {
+                        regions.extend(pat.translated(offset).0);
+                    }
+                }
+                GdsElement::GdsNode(n) if n.layer == layer => {
+                    return Err(PatternError::UnsupportedElement {
+                        cell: name.to_string(),
+                        kind: "node",
+                        layer,
+                    })
+                }
+                GdsElement::GdsNode(_) | GdsElement::GdsTextElem(_) => {}
+            }
+        }
+        walk.leave();
+        Ok((Self(regions), rounding))
+    }
+    /// Bounding box of the cell's geometry on `layer`, found without flattening it and
+    /// remembered per cell for the rest of the walk
+    ///
+    /// Rotated children contribute the box around their own rotated box, so off-axis
+    /// placements can come out slightly larger than the flattened geometry.
+    fn extent(
+        lib: &GdsLibrary,
+        name: &str,
+        layer: i16,
+        opts: &ConvertOptions,
+        walk: &mut Walk,
+    ) -> PatternResult<Option<(Point, Point)>> {
+        if let Some(&extent) = walk.extents.get(name) {
+            return Ok(extent);
+        }
+        let struc = find_struct(lib, name)?;
+        walk.enter(name)?;
+        let mut corners: Vec<Point> = vec![];
+        for elem in &struc.elems {
+            match elem {
+                GdsElement::GdsBoundary(b) if b.layer == layer && opts.keeps(b.datatype) => {
+                    corners.extend(b.xy.iter().map(Point::from))
+                }
+                GdsElement::GdsPath(p) if p.layer == layer && opts.keeps(p.datatype) => {
+                    let region = Region::from_path(p, opts.facets);
+                    corners.extend(region.into_iter().flat_map(|r| r.points))
+                }
+                GdsElement::GdsBox(b) if b.layer == layer && opts.keeps(b.boxtype) => {
+                    corners.extend(b.xy.iter().map(Point::from))
+                }
+                GdsElement::GdsNode(n) if n.layer == layer => {
+                    return Err(PatternError::UnsupportedElement {
+                        cell: name.to_string(),
+                        kind: "node",
+                        layer,
+                    })
+                }
+                GdsElement::GdsStructRef(r) if !walk.skips(lib, opts, &r.name, layer) => {
+                    let t = Transform::from_strans(r.strans.as_ref());
+                    if let Some(bbox) = Self::extent(lib, &r.name, layer, opts, walk)? {
+                        let (lo, hi) = t.apply_to_bbox(bbox);
+                        corners.extend([lo + (&r.xy).into(), hi + (&r.xy).into()]);
+                    }
+                }
+                GdsElement::GdsArrayRef(aref) if !walk.skips(lib, opts, &aref.name, layer) => {
+                    let t = Transform::from_strans(aref.strans.as_ref());
+                    let child = Self::extent(lib, &aref.name, layer, opts, walk)?;
+                    if let (Some(bbox), Some((olo, ohi))) = (child, bounds(array_offsets(aref))) {
+                        let (lo, hi) = t.apply_to_bbox(bbox);
+                        corners.extend([lo + olo, hi + ohi]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        walk.leave();
+        let extent = bounds(corners);
+        walk.extents.insert(name.to_string(), extent);
+        Ok(extent)
+    }
+    /// Appends every region of `other`, for assembling composite outputs from several cells
+    ///
+    /// ```
+    /// use gds21::{GdsBoundary, GdsLibrary, GdsPoint, GdsStruct};
+    /// use gdsii_to_gerber::{ConvertOptions, GerberOptions, Pattern};
+    ///
+    /// let mut lib = GdsLibrary::new("lib");
+    /// for (name, x) in [("A", 0), ("B", 100)] {
+    ///     let mut cell = GdsStruct::new(name);
+    ///     cell.elems.push(
+    ///         GdsBoundary {
+    ///             layer: 1,
+    ///             xy: GdsPoint::vec(&[(x, 0), (x + 10, 0), (x, 10), (x, 0)]),
+    ///             ..Default::default()
+    ///         }
+    ///         .into(),
+    ///     );
+    ///     lib.structs.push(cell);
+    /// }
+    ///
+    /// let opts = ConvertOptions::default();
+    /// let mut merged = Pattern::from_gds_struct(&lib, "A", 1, &opts)?;
+    /// merged.merge(Pattern::from_gds_struct(&lib, "B", 1, &opts)?);
+    /// assert_eq!(merged.len(), 2);
+    ///
+    /// let mut out = vec![];
+    /// merged.write_gerber(&mut out, &lib, &GerberOptions::default())?;
+    /// let gerber = String::from_utf8(out).unwrap();
+    /// assert!(gerber.contains("X0Y10D01*") && gerber.contains("X100Y10D01*"));
+    /// # Ok::<(), gdsii_to_gerber::PatternError>(())
+    /// ```
+    pub fn merge(&mut self, other: Pattern) {
+        self.0.extend(other.0);
+    }
+    /// Copy of the pattern moved by `offset`, leaving the original in place
+    pub fn translated(&self, offset: Point) -> Pattern {
+        self.clone() + offset
+    }
+    /// Every region in the order it is written out
+    ///
+    /// ```
+    /// use gds21::{GdsBoundary, GdsLibrary, GdsPoint, GdsStruct};
+    /// use gdsii_to_gerber::{ConvertOptions, Pattern};
+    ///
+    /// let mut top = GdsStruct::new("TOP");
+    /// for x in [0, 100] {
+    ///     let triangle = [(x, 0), (x + 10, 0), (x + 10, 10), (x, 0)];
+    ///     top.elems.push(
+    ///         GdsBoundary {
+    ///             layer: 1,
+    ///             xy: GdsPoint::vec(&triangle),
+    ///             ..Default::default()
+    ///         }
+    ///         .into(),
+    ///     );
+    /// }
+    /// let mut lib = GdsLibrary::new("lib");
+    /// lib.structs.push(top);
+    ///
+    /// let pattern = Pattern::from_gds_struct(&lib, "TOP", 1, &ConvertOptions::default())?;
+    /// let vertices: usize = pattern.regions().map(|r| r.points().len()).sum();
+    /// assert_eq!(vertices, 8);
+    /// # Ok::<(), gdsii_to_gerber::PatternError>(())
+    /// ```
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.0.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Number of regions contributed by each datatype
+    pub fn datatype_counts(&self) -> BTreeMap<i16, usize> {
+        self.0
+            .iter()
+            .map(Region::datatype)
+            .counts()
+            .into_iter()
+            .collect()
+    }
+    /// Lower-left and upper-right corners enclosing every region
+    pub fn bbox(&self) -> Option<(Point, Point)> {
+        bounds(self.0.iter().flat_map(|r| r.points.iter().copied()))
+    }
+    /// Grows every region by `mm` (shrinks when negative), dropping regions that collapse
+    pub fn biased(self, mm: f64, lib: &GdsLibrary, join: Join, facets: u32) -> Self {
+        let distance = mm / mm_from_gds(1, lib);
+        let total = self.0.len();
+        let (mut collapsed, mut pinched) = (0, 0);
+        let mut regions = vec![];
+        for region in &self.0 {
+            let parts = offset::offset(region, distance, join, facets);
+            match parts.iter().filter(|r| r.signed_area() > 0.).count() {
+                0 => collapsed += 1,
+                1 => {}
+                _ => pinched += 1,
+            }
+            regions.extend(parts);
+        }
+        if collapsed > 0 {
+            warn!("Bias of {mm} mm collapsed {collapsed} of {total} regions, dropping them");
+        }
+        if pinched > 0 {
+            warn!("Bias of {mm} mm pinched off thin parts of {pinched} of {total} regions, splitting them");
+        }
+        Self(regions)
+    }
+    /// Outer boundaries of the union of all regions, dropping any holes
+    pub fn outer_contours(&self) -> Self {
+        Self(
+            union::union(&self.0)
+                .into_iter()
+                .filter(|r| r.signed_area() > 0.)
+                .collect(),
+        )
+    }
+    /// Removes regions enclosing no area, returning how many were dropped
+    pub fn drop_degenerate(&mut self) -> usize {
+        let before = self.0.len();
+        self.0.retain(|r| r.signed_area() != 0.);
+        before - self.0.len()
+    }
+    /// Keeps the `n` regions with the largest area, ties going to the one emitted first
+    pub fn keep_largest(&mut self, n: usize) {
+        self.0
+            .sort_by(|a, b| b.signed_area().abs().total_cmp(&a.signed_area().abs()));
+        self.0.truncate(n);
+    }
+    /// Reorders regions greedily so that each is followed by the one whose centroid is nearest,
+    /// starting from the origin, to shorten plotter travel between them
+    pub fn optimize_travel(&mut self) {
+        let mut remaining = std::mem::take(&mut self.0)
+            .into_iter()
+            .map(|r| (r.centroid(), r))
+            .collect_vec();
+        let mut at = (0., 0.);
+        while let Some((i, _)) = remaining
+            .iter()
+            .enumerate()
+            .min_by(|(_, (a, _)), (_, (b, _))| dist(at, *a).total_cmp(&dist(at, *b)))
+        {
+            let (c, region) = remaining.swap_remove(i);
+            at = c;
+            self.0.push(region);
+        }
+    }
+    pub fn write_gerber(
+        &self,
+        w: &mut impl Write,
+        lib: &GdsLibrary,
+        opts: &GerberOptions,
+    ) -> PatternResult<()> {
+        match opts.style {
+            GerberStyle::Pretty => self.write_commands(w, lib, opts),
+            GerberStyle::Compact => {
+                let mut buf = vec![];
+                self.write_commands(&mut buf, lib, opts)?;
+                buf.retain(|&b| b != b'\n');
+                buf.push(b'\n');
+                w.write_all(&buf).map_err(GerberError::from)?;
+                Ok(())
+            }
+        }
+    }
+    fn write_commands(
+        &self,
+        w: &mut impl Write,
+        lib: &GdsLibrary,
+        opts: &GerberOptions,
+    ) -> PatternResult<()> {
+        self.check_fits(lib, opts.format)?;
+        write_header(w, opts)?;
+        self.write_body(w, lib, opts)?;
+        write_footer(w)
+    }
+    /// Writes the apertures and drawing commands for this pattern, without the header or
+    /// end of file, leaving dark polarity in effect
+    ///
+    /// Aperture codes always start from the same number, so the bodies of two patterns
+    /// cannot be concatenated into one file; use [`Pattern::write_gerber_body`] for that.
+    pub fn write_body(
+        &self,
+        w: &mut impl Write,
+        lib: &GdsLibrary,
+        opts: &GerberOptions,
+    ) -> PatternResult<()> {
+        use gerber_types::*;
+        let co_fmt = opts.format;
+        if opts.outline {
+            ExtendedCode::ApertureDefinition(ApertureDefinition::new(
+                OUTLINE_APERTURE,
+                Aperture::Circle(Circle::new(opts.outline_width)),
+            ))
+            .serialize(w)?;
+            DCode::SelectAperture(OUTLINE_APERTURE).serialize(w)?;
+            GCode::InterpolationMode(InterpolationMode::Linear).serialize(w)?;
+            for region in &self.0 {
+                write_contour(w, region, lib, co_fmt)?;
+            }
+        } else {
+            let prims = self.primitives(opts);
+            let apertures = flash_sizes(&prims);
+            for (code, &(sx, sy)) in (FIRST_FLASH_APERTURE..).zip(&apertures) {
+                let (sx, sy) = (aperture_mm(sx, lib, co_fmt), aperture_mm(sy, lib, co_fmt));
+                ExtendedCode::ApertureDefinition(ApertureDefinition::new(
+                    code,
+                    Aperture::Rectangle(Rectangular::new(sx, sy)),
+                ))
+                .serialize(w)?;
+            }
+            let groups = if opts.nested_polarity {
+                let depths = self.nesting_depths();
+                (0..prims.len())
+                    .sorted_by_key(|&i| depths[i])
+                    .group_by(|&i| depths[i] % 2 == 1)
+                    .into_iter()
+                    .map(|(clear, group)| (clear, group.collect_vec()))
+                    .collect_vec()
+            } else {
+                vec![(false, (0..prims.len()).collect_vec())]
+            };
+            if opts.invert {
+                if let Some(field) = self.bbox().map(Region::rect) {
+                    GCode::RegionMode(true).serialize(w)?;
+                    write_contour(w, &field, lib, co_fmt)?;
+                    GCode::RegionMode(false).serialize(w)?;
+                }
+            }
+            let mut dark = true;
+            for (clear, group) in groups {
+                if (clear != opts.invert) == dark {
+                    dark = !dark;
+                    let polarity = if dark {
+                        Polarity::Dark
+                    } else {
+                        Polarity::Clear
+                    };
+                    ExtendedCode::LoadPolarity(polarity).serialize(w)?;
+                }
+                let group = group.into_iter().map(|i| &prims[i]);
+                write_primitives(w, group, &apertures, lib, co_fmt)?;
+            }
+            if !dark {
+                ExtendedCode::LoadPolarity(Polarity::Dark).serialize(w)?;
+            }
+        }
+        Ok(())
+    }
+    /// Writes just the filled regions of this pattern in dark polarity, using no apertures,
+    /// so that bodies of several patterns can be appended between one [`write_header`]
+    /// and one [`write_footer`]
+    ///
+    /// ```
+    /// use gds21::{GdsBoundary, GdsLibrary, GdsPoint, GdsStruct};
+    /// use gdsii_to_gerber::{write_footer, write_header, ConvertOptions, GerberOptions, Pattern};
+    ///
+    /// let mut lib = GdsLibrary::new("lib");
+    /// for (name, x) in [("A", 0), ("B", 100)] {
+    ///     let mut cell = GdsStruct::new(name);
+    ///     cell.elems.push(
+    ///         GdsBoundary {
+    ///             layer: 1,
+    ///             xy: GdsPoint::vec(&[(x, 0), (x + 10, 0), (x, 10), (x, 0)]),
+    ///             ..Default::default()
+    ///         }
+    ///         .into(),
+    ///     );
+    ///     lib.structs.push(cell);
+    /// }
+    ///
+    /// let opts = GerberOptions::default();
+    /// let mut out = vec![];
+    /// write_header(&mut out, &opts)?;
+    /// for name in ["A", "B"] {
+    ///     let pattern = Pattern::from_gds_struct(&lib, name, 1, &ConvertOptions::default())?;
+    ///     pattern.write_gerber_body(&mut out, &lib, opts.format)?;
+    /// }
+    /// write_footer(&mut out)?;
+    ///
+    /// let gerber = String::from_utf8(out).unwrap();
+    /// assert!(gerber.starts_with("%FSLAX66Y66*%\n%MOMM*%\n"));
+    /// assert_eq!(gerber.matches("G36*").count(), 2);
+    /// assert_eq!(gerber.matches("M02*").count(), 1);
+    /// assert!(gerber.ends_with("G37*\nM02*\n"));
+    /// # Ok::<(), gdsii_to_gerber::PatternError>(())
+    /// ```
+    pub fn write_gerber_body(
+        &self,
+        w: &mut impl Write,
+        lib: &GdsLibrary,
+        co_fmt: CoordinateFormat,
+    ) -> PatternResult<()> {
+        use gerber_types::*;
+        self.check_fits(lib, co_fmt)?;
+        ExtendedCode::LoadPolarity(Polarity::Dark).serialize(w)?;
+        GCode::RegionMode(true).serialize(w)?;
+        for region in &self.0 {
+            write_contour(w, region, lib, co_fmt)?;
+        }
+        GCode::RegionMode(false).serialize(w)?;
+        Ok(())
+    }
+    /// Fails if any coordinate is too large for the integer digits of the format
+    fn check_fits(&self, lib: &GdsLibrary, co_fmt: CoordinateFormat) -> PatternResult<()> {
+        if let Some((lo, hi)) = self.bbox() {
+            let extent = [lo.x, lo.y, hi.x, hi.y]
+                .into_iter()
+                .map(|v| mm_from_gds(v, lib).abs())
+                .fold(0., f64::max);
+            if extent >= 10f64.powi(co_fmt.integer as i32) {
+                let integer = (extent.log10().floor() as u8 + 1).max(1);
+                return Err(PatternError::CoordinateOverflow {
+                    extent,
+                    format: format_name(co_fmt),
+                    suggested: (integer <= 6)
+                        .then(|| format_name(CoordinateFormat::new(integer, co_fmt.decimal))),
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Splits the pattern into consecutive parts whose Gerber output stays within
+    /// `max_bytes` each, never dividing a region or, with nested polarity, separating a
+    /// hole from the region it is cut out of
+    ///
+    /// A pattern that already fits comes back whole as the only part.
+    pub fn split_to_fit(
+        &self,
+        lib: &GdsLibrary,
+        opts: &GerberOptions,
+        max_bytes: usize,
+    ) -> PatternResult<Vec<Pattern>> {
+        let size = |pat: &Pattern| -> PatternResult<usize> {
+            let mut buf = vec![];
+            pat.write_gerber(&mut buf, lib, opts)?;
+            Ok(buf.len())
+        };
+        let whole = size(self)?;
+        if whole <= max_bytes {
+            return Ok(vec![self.clone()]);
+        }
+        let groups = if opts.nested_polarity {
+            let areas = self.0.iter().map(|r| r.signed_area().abs()).collect_vec();
+            let enclosing = self.enclosing();
+            let root = |i: usize| {
+                let outermost = enclosing[i]
+                    .iter()
+                    .max_by(|&&a, &&b| areas[a].total_cmp(&areas[b]));
+                *outermost.unwrap_or(&i)
+            };
+            let mut groups: Vec<Vec<usize>> = vec![];
+            let mut group_of = BTreeMap::new();
+            for i in 0..self.0.len() {
+                let g = *group_of.entry(root(i)).or_insert_with(|| {
+                    groups.push(vec![]);
+                    groups.len() - 1
+                });
+                groups[g].push(i);
+            }
+            groups
+        } else {
+            (0..self.0.len()).map(|i| vec![i]).collect()
+        };
+        let part = |groups: &[Vec<usize>]| -> Pattern {
+            let mut indices = groups.iter().flatten().copied().collect_vec();
+            indices.sort_unstable();
+            indices.into_iter().map(|i| self.0[i].clone()).collect()
+        };
+        // Packing on per-group estimates keeps this linear; each part is still rendered
+        // once to confirm it fits, and halved until it does
+        let overhead = size(&Pattern(vec![]))?;
+        let mut parts: Vec<&[Vec<usize>]> = vec![];
+        let (mut start, mut used) = (0, overhead);
+        for (i, group) in groups.iter().enumerate() {
+            let cost = size(&part(std::slice::from_ref(group)))?.saturating_sub(overhead);
+            if used + cost > max_bytes && i > start {
+                parts.push(&groups[start..i]);
+                (start, used) = (i, overhead);
+            }
+            used += cost;
+        }
+        parts.push(&groups[start..]);
+        let mut out = vec![];
+        while let Some(groups) = parts.pop() {
+            let pat = part(groups);
+            let bytes = size(&pat)?;
+            if bytes <= max_bytes {
+                out.push(pat);
+            } else if groups.len() > 1 {
+                let (a, b) = groups.split_at(groups.len() / 2);
+                parts.extend([a, b]);
+            } else {
+                return Err(PatternError::PartTooLarge {
+                    bytes,
+                    limit: max_bytes,
+                });
+            }
+        }
+        out.reverse();
+        Ok(out)
+    }
+    fn primitives(&self, opts: &GerberOptions) -> Vec<Primitive<'_>> {
+        self.0
+            .iter()
+            .map(|r| match r.as_rect() {
+                Some((lo, hi)) if opts.flash_rects => Primitive::Flash { lo, hi },
+                _ => Primitive::Fill(r),
+            })
+            .collect()
+    }
+    /// Every aperture the Gerber output defines for this pattern, with how often it is used
+    pub fn apertures(&self, lib: &GdsLibrary, opts: &GerberOptions) -> Vec<ApertureUse> {
+        if opts.outline {
+            return vec![ApertureUse {
+                code: OUTLINE_APERTURE,
+                shape: ApertureShape::Circle {
+                    diameter: opts.outline_width,
+                },
+                uses: self.0.len(),
+            }];
+        }
+        let prims = self.primitives(opts);
+        let uses = prims.iter().filter_map(Primitive::flash_size).counts();
+        (FIRST_FLASH_APERTURE..)
+            .zip(flash_sizes(&prims))
+            .map(|(code, size)| ApertureUse {
+                code,
+                shape: ApertureShape::Rectangle {
+                    width: aperture_mm(size.0, lib, opts.format),
+                    height: aperture_mm(size.1, lib, opts.format),
+                },
+                uses: uses[&size],
+            })
+            .collect()
+    }
+    /// For every region, how many larger regions enclose it
+    ///
+    /// Even depths are material and odd depths are holes cut out of the region around
+    /// them, so concentric rings alternate between the two.
+    fn nesting_depths(&self) -> Vec<usize> {
+        self.enclosing().iter().map(Vec::len).collect()
+    }
+    /// For every region, the indices of the larger regions that enclose it
+    fn enclosing(&self) -> Vec<Vec<usize>> {
+        let areas = self.0.iter().map(|r| r.signed_area().abs()).collect_vec();
+        (0..self.0.len())
+            .map(|i| {
+                (0..self.0.len())
+                    .filter(|&j| areas[j] > areas[i] && union::contains(&self.0[j], &self.0[i]))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Settings that control how a pattern is serialized to Gerber
+#[derive(Debug, Clone)]
+pub struct GerberOptions {
+    pub format: CoordinateFormat,
+    /// Stroke region contours with a thin aperture instead of filling them
+    pub outline: bool,
+    /// Diameter of the round aperture used to stroke outlines, in millimeters
+    pub outline_width: f64,
+    pub style: GerberStyle,
+    /// Treat regions enclosed by other regions as holes, alternating polarity by nesting depth
+    pub nested_polarity: bool,
+    /// Flash axis-aligned rectangles with rectangular apertures instead of filling them
+    pub flash_rects: bool,
+    /// Lay a dark field over the bounding box and draw every shape in clear polarity, so
+    /// the image is the complement of the normal output
+    ///
+    /// Unlike `nested_polarity`, which picks polarity per region from its nesting depth,
+    /// this flips the whole layer; combined, holes come out dark inside clear shapes.
+    pub invert: bool,
+    /// Image polarity declared in the header, omitted when `None`
+    pub image_polarity: Option<ImagePolarity>,
+    /// Value of the X2 file function attribute, such as `Other,L3`, omitted when `None`
+    pub file_function: Option<String>,
+}
+
+/// Polarity of the whole image as declared by the file header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImagePolarity {
+    Positive,
+    Negative,
+}
+
+impl FromStr for ImagePolarity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pos" => Ok(Self::Positive),
+            "neg" => Ok(Self::Negative),
+            _ => Err(format!("unknown image polarity `{s}`, expected pos or neg")),
+        }
+    }
+}
+
+impl Default for GerberOptions {
+    fn default() -> Self {
+        Self {
+            format: CoordinateFormat::new(6, 6),
+            outline: false,
+            outline_width: 0.1,
+            style: GerberStyle::Pretty,
+            nested_polarity: false,
+            flash_rects: false,
+            invert: false,
+            image_polarity: None,
+            file_function: None,
+        }
+    }
+}
+
+const FIRST_FLASH_APERTURE: i32 = 11;
+
+/// One aperture definition in a Gerber file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApertureUse {
+    /// D-code the aperture is defined under
+    pub code: i32,
+    pub shape: ApertureShape,
+    /// Number of contours drawn or pads flashed with it
+    pub uses: usize,
+}
+
+/// Shape and size of an aperture, in millimeters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApertureShape {
+    Circle { diameter: f64 },
+    Rectangle { width: f64, height: f64 },
+}
+
+/// Distinct flash sizes, in database units, in the order they first appear
+fn flash_sizes(prims: &[Primitive]) -> Vec<(i32, i32)> {
+    prims
+        .iter()
+        .filter_map(Primitive::flash_size)
+        .unique()
+        .collect()
+}
+
+/// Aperture dimension in millimeters, rounded to the coordinate resolution so that it
+/// prints without float noise
+fn aperture_mm(v: i32, lib: &GdsLibrary, format: CoordinateFormat) -> f64 {
+    let scale = 10f64.powi(format.decimal as i32);
+    (mm_from_gds(v, lib) * scale).round() / scale
+}
+
+/// One item drawn on a filled layer
+enum Primitive<'a> {
+    /// Contour filled in region mode
+    Fill(&'a Region),
+    /// Axis-aligned rectangle between two corners, flashed with a rectangular aperture
+    Flash { lo: Point, hi: Point },
+}
+
+impl Primitive<'_> {
+    fn flash_size(&self) -> Option<(i32, i32)> {
+        match self {
+            Primitive::Fill(_) => None,
+            Primitive::Flash { lo, hi } => Some((hi.x - lo.x, hi.y - lo.y)),
+        }
+    }
+}
+
+/// Writes fills and flashes in order, entering region mode only around runs of fills
+///
+/// `apertures` lists the flash sizes in the order their D-codes were defined.
+fn write_primitives<'a>(
+    w: &mut impl Write,
+    prims: impl IntoIterator<Item = &'a Primitive<'a>>,
+    apertures: &[(i32, i32)],
+    lib: &GdsLibrary,
+    format: CoordinateFormat,
+) -> PatternResult<()> {
+    use gerber_types::*;
+    let mut in_region = false;
+    let mut selected = None;
+    for prim in prims {
+        match prim {
+            Primitive::Fill(region) => {
+                if !in_region {
+                    GCode::RegionMode(true).serialize(w)?;
+                    in_region = true;
+                }
+                write_contour(w, region, lib, format)?;
+            }
+            Primitive::Flash { lo, hi } => {
+                if in_region {
+                    GCode::RegionMode(false).serialize(w)?;
+                    in_region = false;
+                }
+                let size = prim.flash_size();
+                let index = apertures.iter().position(|&s| Some(s) == size).unwrap();
+                let code = FIRST_FLASH_APERTURE + index as i32;
+                if selected != Some(code) {
+                    DCode::SelectAperture(code).serialize(w)?;
+                    selected = Some(code);
+                }
+                let center = |a: i32, b: i32| {
+                    let mm = (a as f64 + b as f64) / 2. * lib.units.db_unit() * 1000.;
+                    <CoordinateNumber as conv::TryFrom<f64>>::try_from(mm).unwrap()
+                };
+                DCode::Operation(Operation::Flash(Coordinates {
+                    x: Some(center(lo.x, hi.x)),
+                    y: Some(center(lo.y, hi.y)),
+                    format,
+                }))
+                .serialize(w)?;
+            }
+        }
+    }
+    if in_region {
+        GCode::RegionMode(false).serialize(w)?;
+    }
+    Ok(())
+}
+
+/// Writes the coordinate format, units and image polarity that open every Gerber file
+pub fn write_header(w: &mut impl Write, opts: &GerberOptions) -> PatternResult<()> {
+    use gerber_types::*;
+    let co_fmt = opts.format;
+    ExtendedCode::CoordinateFormat(co_fmt).serialize(w)?;
+    ExtendedCode::Unit(gerber_types::Unit::Millimeters).serialize(w)?;
+    if let Some(polarity) = opts.image_polarity {
+        // IP predates attributes and has no code in gerber_types, so it is written raw;
+        // the file polarity attribute is its modern counterpart
+        let (ip, attr) = match polarity {
+            ImagePolarity::Positive => ("POS", FilePolarity::Positive),
+            ImagePolarity::Negative => ("NEG", FilePolarity::Negative),
+        };
+        writeln!(w, "%IP{ip}*%").map_err(GerberError::from)?;
+        ExtendedCode::FileAttribute(FileAttribute::FilePolarity(attr)).serialize(w)?;
+    }
+    if let Some(function) = &opts.file_function {
+        // gerber_types panics serializing FileFunction::Other, so this attribute is raw too
+        writeln!(w, "%TF.FileFunction,{function}*%").map_err(GerberError::from)?;
+    }
+    Ok(())
+}
+
+/// Writes the end-of-file command that closes every Gerber file
+pub fn write_footer(w: &mut impl Write) -> PatternResult<()> {
+    use gerber_types::*;
+    MCode::EndOfFile.serialize(w)?;
+    Ok(())
+}
+
+/// Writes one closed contour as a move to its first point followed by a draw to every point
+fn write_contour(
+    w: &mut impl Write,
+    region: &Region,
+    lib: &GdsLibrary,
+    format: CoordinateFormat,
+) -> PatternResult<()> {
+    use gerber_types::*;
+    let coords = |p: &Point| Coordinates {
+        x: Some(coord_from_gds(p.x, lib)),
+        y: Some(coord_from_gds(p.y, lib)),
+        format,
+    };
+    DCode::Operation(Operation::Move(coords(&region.points[0]))).serialize(w)?;
+    for point in &region.points {
+        DCode::Operation(Operation::Interpolate(coords(point), None)).serialize(w)?;
+    }
+    Ok(())
+}
+
+/// Text layout of the emitted Gerber commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GerberStyle {
+    /// One command per line
+    Pretty,
+    /// All commands packed onto a single line, for parsers that choke on line breaks
+    Compact,
+}
+
+impl FromStr for GerberStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            _ => Err(format!(
+                "unknown Gerber style `{s}`, expected pretty or compact"
+            )),
+        }
+    }
+}
+
+const OUTLINE_APERTURE: i32 = 10;
+
+/// Accumulated rounding, in database units, above which flattening warns about lost fidelity
+const ROUNDING_WARN_DB: f64 = 0.5;
+
+/// Parses an inclusive datatype range written as `A-B`
+pub fn parse_datatype_range(s: &str) -> Result<RangeInclusive<i16>, String> {
+    let (lo, hi) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected a range like 1-3, got `{s}`"))?;
+    let bound = |v: &str| {
+        v.trim()
+            .parse::<i16>()
+            .map_err(|_| format!("invalid datatype `{v}` in range `{s}`"))
+    };
+    let (lo, hi) = (bound(lo)?, bound(hi)?);
+    if lo > hi {
+        return Err(format!("datatype range `{s}` is empty"));
+    }
+    Ok(lo..=hi)
+}
+
+pub fn parse_coord_format(s: &str) -> Result<CoordinateFormat, String> {
+    let (integer, decimal) = s
+        .split_once('.')
+        .ok_or("expected <INTEGER>.<DECIMAL> digits, e.g. 6.6")?;
+    let integer: u8 = integer.parse().map_err(|e| format!("{e}"))?;
+    let decimal: u8 = decimal.parse().map_err(|e| format!("{e}"))?;
+    if !(1..=6).contains(&integer) || !(4..=6).contains(&decimal) {
+        return Err("Gerber allows 1-6 integer and 4-6 decimal digits".into());
+    }
+    Ok(CoordinateFormat::new(integer, decimal))
+}
+
+/// Rejects a library whose database unit is zero, negative or not a number, as every
+/// coordinate would scale into garbage
+pub fn check_units(lib: &GdsLibrary) -> PatternResult<()> {
+    let db_unit = lib.units.db_unit();
+    if !db_unit.is_finite() || db_unit <= 0. {
+        return Err(PatternError::InvalidUnits(db_unit));
+    }
+    Ok(())
+}
+
+/// Warns if the library's database unit is finer than the resolution of the coordinate
+/// format, since coordinates are then rounded and detail below the resolution is lost
+pub fn warn_precision_loss(lib: &GdsLibrary, format: CoordinateFormat) {
+    let db_mm = mm_from_gds(1, lib);
+    let resolution = 10f64.powi(-(format.decimal as i32));
+    if db_mm >= resolution * (1. - 1e-9) {
+        return;
+    }
+    let needed = (-db_mm.log10() - 1e-9).ceil() as u8;
+    let advice = if needed <= 6 {
+        let fmt = format_name(CoordinateFormat::new(format.integer, needed));
+        format!("use --coord-format {fmt}")
+    } else {
+        "Gerber cannot go finer than 6 decimals, so coarsen the design's units".into()
+    };
+    warn!(
+        "The database unit of {} m is finer than the {} format's resolution of {resolution} mm, features below it will be lost; {advice}",
+        lib.units.db_unit(),
+        format_name(format)
+    );
+}
+
+/// Number of the library's user units in one millimeter, for reports shown in the units
+/// the layout was drawn in
+///
+/// gds21's `user_unit` divides the user unit in database units by the database unit in
+/// meters, which gives user units per meter rather than the meters its docs promise.
+pub fn user_units_per_mm(lib: &GdsLibrary) -> f64 {
+    lib.units.user_unit() * 1e-3
+}
+
+fn overflow_advice(suggested: &Option<String>) -> String {
+    match suggested {
+        Some(fmt) => format!("use --coord-format {fmt}"),
+        None => "no Gerber format is wide enough, so coarsen the design's units or move the origin with --center".into(),
+    }
+}
+
+fn format_name(f: CoordinateFormat) -> String {
+    format!("{}.{}", f.integer, f.decimal)
+}
+
+fn dist(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+/// Fewest decimal places that hold every multiple of the database unit in millimeters,
+/// capped at what an `f64` can carry
+fn mm_decimals(lib: &GdsLibrary) -> i32 {
+    let db_mm = mm_from_gds(1, lib);
+    (0..15)
+        .find(|&d| {
+            let scaled = db_mm * 10f64.powi(d);
+            (scaled - scaled.round()).abs() < 1e-6 * scaled
+        })
+        .unwrap_or(15)
+}
+
+fn mm_from_gds(v: i32, lib: &GdsLibrary) -> f64 {
+    let unit = lib.units.db_unit();
+    let meters = v as f64 * unit;
+    meters * 1000.
+}
+
+fn coord_from_gds(v: i32, lib: &GdsLibrary) -> CoordinateNumber {
+    <CoordinateNumber as conv::TryFrom<f64>>::try_from(mm_from_gds(v, lib)).unwrap()
+}
+
+impl Pattern {
+    /// Applies `t` to every point, also returning the largest distance any point moved
+    /// when rounded back onto the database grid
+    fn transformed(mut self, t: &Transform) -> (Self, f64) {
+        let mut rounding: f64 = 0.;
+        if !t.is_identity() {
+            for r in &mut self.0 {
+                for p in &mut r.points {
+                    let (x, y) = t.apply_exact(*p);
+                    *p = Point::from((x, y));
+                    rounding = rounding.max((x - p.x as f64).hypot(y - p.y as f64));
+                }
+            }
+        }
+        (self, rounding)
+    }
+}
+
+impl FromIterator<Region> for Pattern {
+    fn from_iter<T: IntoIterator<Item = Region>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl std::ops::Add<Point> for Pattern {
+    type Output = Pattern;
+
+    fn add(mut self, rhs: Point) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+impl Extend<Region> for Pattern {
+    fn extend<T: IntoIterator<Item = Region>>(&mut self, iter: T) {
+        self.0.extend(iter)
+    }
+}
+
+impl std::ops::AddAssign<Point> for Pattern {
+    fn add_assign(&mut self, rhs: Point) {
+        for r in &mut self.0 {
+            *r += rhs;
+        }
+    }
+}
+
+/// Closed polygon whose last point repeats the first
+#[derive(Debug, Clone)]
+pub struct Region {
+    points: Vec<Point>,
+    datatype: i16,
+    properties: Vec<(i16, String)>,
+}
+impl<I> FromIterator<I> for Region
+where
+    I: Into<Point>,
+{
+    fn from_iter<T: IntoIterator<Item = I>>(iter: T) -> Self {
+        Self {
+            points: iter.into_iter().map(|v| v.into()).collect(),
+            datatype: 0,
+            properties: vec![],
+        }
+    }
+}
+impl Region {
+    /// Vertices in database units
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+    /// GDS datatype of the element this region came from
+    pub fn datatype(&self) -> i16 {
+        self.datatype
+    }
+    fn with_datatype(mut self, datatype: i16) -> Self {
+        self.datatype = datatype;
+        self
+    }
+    /// GDS properties (attribute number and value) of the element this region came from
+    pub fn properties(&self) -> &[(i16, String)] {
+        &self.properties
+    }
+    fn with_properties(mut self, properties: &[GdsProperty]) -> Self {
+        self.properties = properties
+            .iter()
+            .map(|p| (p.attr, p.value.clone()))
+            .collect();
+        self
+    }
+    /// Carries the datatype and properties of `source` over to this derived region
+    pub(crate) fn with_attributes_of(mut self, source: &Region) -> Self {
+        self.datatype = source.datatype;
+        self.properties = source.properties.clone();
+        self
+    }
+    /// Closed counter-clockwise rectangle spanning the two corners
+    pub fn rect((lo, hi): (Point, Point)) -> Self {
+        [
+            lo,
+            Point { x: hi.x, y: lo.y },
+            hi,
+            Point { x: lo.x, y: hi.y },
+            lo,
+        ]
+        .into_iter()
+        .collect()
+    }
+    /// Outlines a path as a closed region, squaring off or rounding its ends per `path_type`
+    fn from_path(path: &GdsPath, facets: u32) -> Option<Self> {
+        let mut pts: Vec<(f64, f64)> = path.xy.iter().map(|p| (p.x as f64, p.y as f64)).collect();
+        pts.dedup();
+        if pts.len() < 2 {
+            return None;
+        }
+        let half = path.width.unwrap_or(0).unsigned_abs() as f64 / 2.;
+        if half == 0. {
+            return None;
+        }
+        let path_type = path.path_type.unwrap_or(0);
+        let (begin_extn, end_extn) = match path_type {
+            2 => (half, half),
+            4 => (
+                path.begin_extn.unwrap_or(0) as f64,
+                path.end_extn.unwrap_or(0) as f64,
+            ),
+            _ => (0., 0.),
+        };
+        let unit = |a: (f64, f64), b: (f64, f64)| {
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let len = dx.hypot(dy);
+            (dx / len, dy / len)
+        };
+        let last = pts.len() - 1;
+        let start_dir = unit(pts[0], pts[1]);
+        let end_dir = unit(pts[last - 1], pts[last]);
+        pts[0].0 -= start_dir.0 * begin_extn;
+        pts[0].1 -= start_dir.1 * begin_extn;
+        pts[last].0 += end_dir.0 * end_extn;
+        pts[last].1 += end_dir.1 * end_extn;
+        // Left-hand normal at every vertex, mitered at the interior joins
+        let offsets: Vec<(f64, f64)> = (0..pts.len())
+            .map(|i| {
+                let n_in = (i > 0).then(|| unit(pts[i - 1], pts[i]));
+                let n_out = (i < last).then(|| unit(pts[i], pts[i + 1]));
+                let (d1, d2) = match (n_in, n_out) {
+                    (Some(d1), Some(d2)) => (d1, d2),
+                    (Some(d), None) | (None, Some(d)) => (d, d),
+                    (None, None) => unreachable!(),
+                };
+                let (n1, n2) = ((-d1.1, d1.0), (-d2.1, d2.0));
+                let denom = 1. + n1.0 * n2.0 + n1.1 * n2.1;
+                if denom < 1e-9 {
+                    (n1.0 * half, n1.1 * half)
+                } else {
+                    ((n1.0 + n2.0) / denom * half, (n1.1 + n2.1) / denom * half)
+                }
+            })
+            .collect();
+        let arc = |center: (f64, f64), from: (f64, f64)| {
+            let steps = (facets / 2).max(2);
+            let start = from.1.atan2(from.0);
+            (1..steps).map(move |k| {
+                let a = start - std::f64::consts::PI * k as f64 / steps as f64;
+                (center.0 + half * a.cos(), center.1 + half * a.sin())
+            })
+        };
+        let mut outline: Vec<(f64, f64)> = vec![];
+        outline.extend(
+            pts.iter()
+                .zip(&offsets)
+                .map(|(p, o)| (p.0 + o.0, p.1 + o.1)),
+        );
+        if path_type == 1 {
+            outline.extend(arc(pts[last], offsets[last]));
+        }
+        outline.extend(
+            pts.iter()
+                .zip(&offsets)
+                .rev()
+                .map(|(p, o)| (p.0 - o.0, p.1 - o.1)),
+        );
+        if path_type == 1 {
+            outline.extend(arc(pts[0], (-offsets[0].0, -offsets[0].1)));
+        }
+        outline.push(outline[0]);
+        Some(outline.into_iter().map(Point::from).collect())
+    }
+    /// Vertices in millimeters, see [`Point::as_mm`]
+    pub fn to_mm_points(&self, lib: &GdsLibrary) -> Vec<(f64, f64)> {
+        self.points.iter().map(|p| p.as_mm(lib)).collect()
+    }
+    /// Center of mass of the enclosed area, or the mean of the vertices if there is none
+    pub fn centroid(&self) -> (f64, f64) {
+        let area = self.signed_area();
+        if area == 0. {
+            let open = match self.points.split_last() {
+                Some((last, rest)) if Some(last) == rest.first() => rest,
+                _ => &self.points,
+            };
+            let n = open.len().max(1) as f64;
+            let (sx, sy) = open
+                .iter()
+                .fold((0., 0.), |(sx, sy), p| (sx + p.x as f64, sy + p.y as f64));
+            return (sx / n, sy / n);
+        }
+        let (cx, cy) =
+            self.points
+                .iter()
+                .circular_tuple_windows()
+                .fold((0., 0.), |(cx, cy), (a, b)| {
+                    let (ax, ay, bx, by) = (a.x as f64, a.y as f64, b.x as f64, b.y as f64);
+                    let cross = ax * by - bx * ay;
+                    (cx + (ax + bx) * cross, cy + (ay + by) * cross)
+                });
+        (cx / (6. * area), cy / (6. * area))
+    }
+    /// Corners of the region if it is an axis-aligned rectangle
+    pub fn as_rect(&self) -> Option<(Point, Point)> {
+        let mut pts = self.points.clone();
+        if pts.len() > 1 && pts.first() == pts.last() {
+            pts.pop();
+        }
+        let (lo, hi) = bounds(pts.iter().copied())?;
+        let corners = [
+            lo,
+            Point { x: hi.x, y: lo.y },
+            hi,
+            Point { x: lo.x, y: hi.y },
+        ];
+        let is_rect = pts.len() == 4
+            && lo.x < hi.x
+            && lo.y < hi.y
+            && corners.iter().all(|c| pts.contains(c))
+            && pts
+                .iter()
+                .circular_tuple_windows()
+                .all(|(a, b)| a.x == b.x || a.y == b.y);
+        is_rect.then_some((lo, hi))
+    }
+    /// Shoelace area in square database units, positive for counter-clockwise regions
+    pub fn signed_area(&self) -> f64 {
+        let twice: i128 = self
+            .points
+            .iter()
+            .circular_tuple_windows()
+            .map(|(a, b)| a.x as i128 * b.y as i128 - b.x as i128 * a.y as i128)
+            .sum();
+        twice as f64 / 2.
+    }
+}
+impl std::ops::AddAssign<Point> for Region {
+    fn add_assign(&mut self, rhs: Point) {
+        for p in &mut self.points {
+            *p = *p + rhs;
+        }
+    }
+}
+
+/// Location in database units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+impl Point {
+    /// Coordinates in millimeters, scaled by the library's database unit and rounded to the
+    /// decimals it resolves, so that they print without float noise
+    ///
+    /// ```
+    /// use gds21::{GdsLibrary, GdsUnits};
+    /// use gdsii_to_gerber::{Point, Region};
+    ///
+    /// // One database unit is a micrometer
+    /// let mut lib = GdsLibrary::new("lib");
+    /// lib.units = GdsUnits::new(1e-3, 1e-6);
+    ///
+    /// assert_eq!(Point { x: 1500, y: -250 }.as_mm(&lib), (1.5, -0.25));
+    /// let square = Region::rect((Point { x: 0, y: 0 }, Point { x: 2000, y: 2000 }));
+    /// assert_eq!(
+    ///     square.to_mm_points(&lib),
+    ///     [(0., 0.), (2., 0.), (2., 2.), (0., 2.), (0., 0.)]
+    /// );
+    /// ```
+    pub fn as_mm(&self, lib: &GdsLibrary) -> (f64, f64) {
+        let scale = 10f64.powi(mm_decimals(lib));
+        let round = |v| (mm_from_gds(v, lib) * scale).round() / scale;
+        (round(self.x), round(self.y))
+    }
+    /// Nearest database-unit point to a location in millimeters
+    pub fn from_mm((x, y): (f64, f64), lib: &GdsLibrary) -> Self {
+        let unit = mm_from_gds(1, lib);
+        Point::from((x / unit, y / unit))
+    }
+}
+impl std::ops::Add for Point {
+    type Output = Point;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+        }
+    }
+}
+impl From<(f64, f64)> for Point {
+    fn from((x, y): (f64, f64)) -> Self {
+        Self {
+            x: x.round() as i32,
+            y: y.round() as i32,
+        }
+    }
+}
+impl From<&GdsPoint> for Point {
+    fn from(p: &GdsPoint) -> Self {
+        Self { x: p.x, y: p.y }
+    }
+}
+
+/// Lower-left and upper-right corners enclosing every region of every pattern
+///
+/// This is the one extent calculation that bounding-box outlines, recentering and previews
+/// share, taken over the final geometry after every transform has been applied.
+pub fn combined_bbox<'a>(
+    patterns: impl IntoIterator<Item = &'a Pattern>,
+) -> Option<(Point, Point)> {
+    bounds(
+        patterns
+            .into_iter()
+            .filter_map(Pattern::bbox)
+            .flat_map(|(lo, hi)| [lo, hi]),
+    )
+}
+
+/// Lower-left and upper-right corners enclosing all the points
+pub fn bounds(points: impl IntoIterator<Item = Point>) -> Option<(Point, Point)> {
+    let mut points = points.into_iter();
+    let first = points.next()?;
+    Some(points.fold((first, first), |(lo, hi), p| {
+        (
+            Point {
+                x: lo.x.min(p.x),
+                y: lo.y.min(p.y),
+            },
+            Point {
+                x: hi.x.max(p.x),
+                y: hi.y.max(p.y),
+            },
+        )
+    }))
+}
+
+fn find_struct<'a>(lib: &'a GdsLibrary, name: &str) -> PatternResult<&'a GdsStruct> {
+    lib.structs
+        .iter()
+        .find(|s| s.name == name)
+        .ok_or_else(|| PatternError::PatternDoesNotExist(name.to_string()))
+}
+
+/// Calls `visit` on every element of the named cell and of each cell it references, once
+/// per cell, without descending into references to cells missing from the library or
+/// that `descend` turns down
+fn visit_hierarchy<'a>(
+    lib: &'a GdsLibrary,
+    name: &str,
+    descend: &impl Fn(&str) -> bool,
+    visit: &mut impl FnMut(&'a GdsElement),
+) -> PatternResult<()> {
+    fn walk<'a>(
+        lib: &'a GdsLibrary,
+        struc: &'a GdsStruct,
+        seen: &mut HashSet<&'a str>,
+        descend: &impl Fn(&str) -> bool,
+        visit: &mut impl FnMut(&'a GdsElement),
+    ) {
+        if !seen.insert(&struc.name) {
+            return;
+        }
+        for elem in &struc.elems {
+            visit(elem);
+            let child = match elem {
+                GdsElement::GdsStructRef(r) => &r.name,
+                GdsElement::GdsArrayRef(r) => &r.name,
+                _ => continue,
+            };
+            if !descend(child) {
+                continue;
+            }
+            if let Ok(child) = find_struct(lib, child) {
+                walk(lib, child, seen, descend, visit);
+            }
+        }
+    }
+    walk(
+        lib,
+        find_struct(lib, name)?,
+        &mut HashSet::new(),
+        descend,
+        visit,
+    );
+    Ok(())
+}
+
+/// Layers each cell of a library draws on, including through its references, so that
+/// flattening can skip references with nothing on the layer being converted
+#[derive(Debug, Clone, Default)]
+pub struct CellIndex {
+    layers: HashMap<String, BTreeSet<i16>>,
+}
+
+impl CellIndex {
+    pub fn new(lib: &GdsLibrary) -> Self {
+        Self {
+            layers: transitive_layers(lib),
+        }
+    }
+    /// Whether placing the named cell can contribute anything on `layer`; cells missing
+    /// from the index, because they are missing from the library or sit on or above a
+    /// reference cycle, are assumed to, so that resolving them reports the error
+    fn reaches(&self, name: &str, layer: i16) -> bool {
+        self.layers.get(name).is_none_or(|l| l.contains(&layer))
+    }
+}
+
+/// State shared across one flattening of a cell hierarchy
+struct Walk<'a> {
+    index: &'a CellIndex,
+    /// Cells currently being resolved, outermost first
+    stack: Vec<String>,
+    /// Cells resolved so far, counting each placement separately
+    visits: usize,
+    /// Bounding boxes of cells collapsed to placeholders, by cell name
+    extents: HashMap<String, Option<(Point, Point)>>,
+}
+
+impl<'a> Walk<'a> {
+    fn new(index: &'a CellIndex) -> Self {
+        Self {
+            index,
+            stack: vec![],
+            visits: 0,
+            extents: HashMap::new(),
+        }
+    }
+    /// Starts resolving the named cell, failing if it is already being resolved further up
+    fn enter(&mut self, name: &str) -> PatternResult<()> {
+        if let Some(start) = self.stack.iter().position(|s| s == name) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(name.to_string());
+            return Err(PatternError::CyclicReference(cycle));
+        }
+        self.stack.push(name.to_string());
+        self.visits += 1;
+        Ok(())
+    }
+    fn leave(&mut self) {
+        self.stack.pop();
+    }
+    /// Whether a reference to `child` is passed over: excluded, missing from the library
+    /// while that is allowed, or known to place nothing on `layer`
+    fn skips(&self, lib: &GdsLibrary, opts: &ConvertOptions, child: &str, layer: i16) -> bool {
+        opts.exclude_cells.iter().any(|c| c == child)
+            || (opts.allow_missing_refs && find_struct(lib, child).is_err())
+            || !self.index.reaches(child, layer)
+    }
+}
+
+/// Layered elements of every cell, followed through references
+///
+/// Cells on a reference cycle, or placing one somewhere below them, are left out: their
+/// sets would miss whatever comes back around the cycle, and pruning them could hide the
+/// cycle from flattening.
+fn transitive_layers(lib: &GdsLibrary) -> HashMap<String, BTreeSet<i16>> {
+    struct Visit<'a> {
+        layers: HashMap<String, BTreeSet<i16>>,
+        done: HashSet<&'a str>,
+        active: Vec<&'a str>,
+        cyclic: HashSet<&'a str>,
+    }
+    fn visit<'a>(lib: &'a GdsLibrary, struc: &'a GdsStruct, v: &mut Visit<'a>) {
+        let name = struc.name.as_str();
+        if let Some(start) = v.active.iter().position(|&s| s == name) {
+            v.cyclic.extend(&v.active[start..]);
+            return;
+        }
+        if v.done.contains(name) {
+            return;
+        }
+        v.active.push(name);
+        let mut own = BTreeSet::new();
+        for elem in &struc.elems {
+            let child = match elem {
+                GdsElement::GdsBoundary(b) => {
+                    own.insert(b.layer);
+                    continue;
+                }
+                GdsElement::GdsPath(p) => {
+                    own.insert(p.layer);
+                    continue;
+                }
+                GdsElement::GdsBox(b) => {
+                    own.insert(b.layer);
+                    continue;
+                }
+                GdsElement::GdsNode(n) => {
+                    own.insert(n.layer);
+                    continue;
+                }
+                GdsElement::GdsStructRef(r) => &r.name,
+                GdsElement::GdsArrayRef(r) => &r.name,
+                GdsElement::GdsTextElem(_) => continue,
+            };
+            if let Ok(child) = find_struct(lib, child) {
+                visit(lib, child, v);
+                if v.cyclic.contains(child.name.as_str()) {
+                    v.cyclic.insert(name);
+                }
+                own.extend(v.layers.get(&child.name).into_iter().flatten());
+            }
+        }
+        v.active.pop();
+        v.done.insert(name);
+        if !v.cyclic.contains(name) {
+            v.layers.insert(struc.name.clone(), own);
+        }
+    }
+    let mut v = Visit {
+        layers: HashMap::new(),
+        done: HashSet::new(),
+        active: vec![],
+        cyclic: HashSet::new(),
+    };
+    for struc in &lib.structs {
+        visit(lib, struc, &mut v);
+    }
+    v.layers
+}
+
+/// Every layer carrying convertible geometry anywhere in the named cell's hierarchy
+pub fn cell_layers(lib: &GdsLibrary, name: &str) -> PatternResult<BTreeSet<i16>> {
+    let mut layers = BTreeSet::new();
+    visit_hierarchy(lib, name, &|_| true, &mut |elem| match elem {
+        GdsElement::GdsBoundary(b) => {
+            layers.insert(b.layer);
+        }
+        GdsElement::GdsPath(p) => {
+            layers.insert(p.layer);
+        }
+        GdsElement::GdsBox(b) => {
+            layers.insert(b.layer);
+        }
+        _ => {}
+    })?;
+    Ok(layers)
+}
+
+/// Layers that flattening the named cell with `opts` would find geometry on, leaving out
+/// excluded cells and filtered datatypes
+pub fn converted_layers(
+    lib: &GdsLibrary,
+    name: &str,
+    opts: &ConvertOptions,
+) -> PatternResult<BTreeSet<i16>> {
+    let mut layers = BTreeSet::new();
+    let descend = |child: &str| !opts.exclude_cells.iter().any(|c| c == child);
+    visit_hierarchy(lib, name, &descend, &mut |elem| {
+        let (layer, datatype) = match elem {
+            GdsElement::GdsBoundary(b) => (b.layer, b.datatype),
+            GdsElement::GdsPath(p) => (p.layer, p.datatype),
+            GdsElement::GdsBox(b) => (b.layer, b.boxtype),
+            _ => return,
+        };
+        if opts.keeps(datatype) {
+            layers.insert(layer);
+        }
+    })?;
+    Ok(layers)
+}
+
+/// Names referenced somewhere in the named cell's hierarchy that no cell in the library has
+pub fn missing_refs(lib: &GdsLibrary, name: &str) -> PatternResult<BTreeSet<String>> {
+    let mut missing = BTreeSet::new();
+    visit_hierarchy(lib, name, &|_| true, &mut |elem| {
+        let child = match elem {
+            GdsElement::GdsStructRef(r) => &r.name,
+            GdsElement::GdsArrayRef(r) => &r.name,
+            _ => return,
+        };
+        if find_struct(lib, child).is_err() {
+            missing.insert(child.clone());
+        }
+    })?;
+    Ok(missing)
+}
+
+/// Tally of the elements placed directly in one cell, not following references
+#[derive(Debug, Default, Clone)]
+pub struct ElementCounts {
+    pub refs: usize,
+    pub arefs: usize,
+    pub layers: BTreeMap<i16, LayerCounts>,
+}
+
+/// Tally of the layered elements of one cell on a single layer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LayerCounts {
+    pub boundaries: usize,
+    pub paths: usize,
+    pub boxes: usize,
+    pub texts: usize,
+}
+
+/// Counts each kind of element in the cell without converting any of them
+pub fn element_counts(struc: &GdsStruct) -> ElementCounts {
+    let mut counts = ElementCounts::default();
+    for elem in &struc.elems {
+        let layers = &mut counts.layers;
+        match elem {
+            GdsElement::GdsBoundary(b) => layers.entry(b.layer).or_default().boundaries += 1,
+            GdsElement::GdsPath(p) => layers.entry(p.layer).or_default().paths += 1,
+            GdsElement::GdsBox(b) => layers.entry(b.layer).or_default().boxes += 1,
+            GdsElement::GdsTextElem(t) => layers.entry(t.layer).or_default().texts += 1,
+            GdsElement::GdsStructRef(_) => counts.refs += 1,
+            GdsElement::GdsArrayRef(_) => counts.arefs += 1,
+            GdsElement::GdsNode(_) => {}
+        }
+    }
+    counts
+}
+
+/// Layers drawn on by any boundary, path or box anywhere in the library, referenced or not
+pub fn library_layers(lib: &GdsLibrary) -> BTreeSet<i16> {
+    lib.structs
+        .iter()
+        .flat_map(|s| &s.elems)
+        .filter_map(|elem| match elem {
+            GdsElement::GdsBoundary(b) => Some(b.layer),
+            GdsElement::GdsPath(p) => Some(p.layer),
+            GdsElement::GdsBox(b) => Some(b.layer),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Placement of every instance of an array reference
+///
+/// GDS stores the column and row corner points already rotated, reflected and magnified
+/// by the array's strans, so the steps between instances are taken from them as-is; the
+/// strans only needs applying to the instance geometry itself.
+fn array_offsets(aref: &GdsArrayRef) -> impl Iterator<Item = Point> {
+    let [origin, col_end, row_end] = aref.xy.clone();
+    let step = |end: &GdsPoint, n: i16| {
+        let n = n.max(1) as f64;
+        ((end.x - origin.x) as f64 / n, (end.y - origin.y) as f64 / n)
+    };
+    let (col_step, row_step) = (step(&col_end, aref.cols), step(&row_end, aref.rows));
+    (0..aref.cols)
+        .cartesian_product(0..aref.rows)
+        .map(move |(c, r)| {
+            let (c, r) = (c as f64, r as f64);
+            Point::from((
+                origin.x as f64 + c * col_step.0 + r * row_step.0,
+                origin.y as f64 + c * col_step.1 + r * row_step.1,
+            ))
+        })
+}
+
+/// Linear part of a reference's strans: reflect about x, then magnify, then rotate
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transform {
+    xx: f64,
+    xy: f64,
+    yx: f64,
+    yy: f64,
+}
+impl Transform {
+    const IDENTITY: Self = Self {
+        xx: 1.,
+        xy: 0.,
+        yx: 0.,
+        yy: 1.,
+    };
+    fn from_strans(strans: Option<&GdsStrans>) -> Self {
+        let Some(strans) = strans else {
+            return Self::IDENTITY;
+        };
+        let mag = strans.mag.unwrap_or(1.);
+        let (sin, cos) = match strans.angle.unwrap_or(0.).rem_euclid(360.) {
+            0. => (0., 1.),
+            90. => (1., 0.),
+            180. => (0., -1.),
+            270. => (-1., 0.),
+            a => a.to_radians().sin_cos(),
+        };
+        let flip = if strans.reflected { -1. } else { 1. };
+        Self {
+            xx: mag * cos,
+            xy: -mag * sin * flip,
+            yx: mag * sin,
+            yy: mag * cos * flip,
+        }
+    }
+    fn is_identity(&self) -> bool {
+        *self == Self::IDENTITY
+    }
+    fn apply_exact(&self, p: Point) -> (f64, f64) {
+        let (x, y) = (p.x as f64, p.y as f64);
+        (self.xx * x + self.xy * y, self.yx * x + self.yy * y)
+    }
+    /// Box around the transformed corners of `(lo, hi)`, widened outward to whole units
+    fn apply_to_bbox(&self, (lo, hi): (Point, Point)) -> (Point, Point) {
+        let corners = [(lo.x, lo.y), (hi.x, lo.y), (hi.x, hi.y), (lo.x, hi.y)]
+            .map(|(x, y)| self.apply_exact(Point { x, y }));
+        let min = |f: fn(&(f64, f64)) -> f64| corners.iter().map(f).fold(f64::MAX, f64::min);
+        let max = |f: fn(&(f64, f64)) -> f64| corners.iter().map(f).fold(f64::MIN, f64::max);
+        (
+            Point {
+                x: min(|c| c.0).floor() as i32,
+                y: min(|c| c.1).floor() as i32,
+            },
+            Point {
+                x: max(|c| c.0).ceil() as i32,
+                y: max(|c| c.1).ceil() as i32,
+            },
+        )
+    }
+    /// Factor by which the transform stretches distances
+    fn scale(&self) -> f64 {
+        (self.xx * self.yy - self.xy * self.yx).abs().sqrt()
+    }
+}
+
+pub type PatternResult<T> = Result<T, PatternError>;
+
+#[derive(Error, Debug)]
+pub enum PatternError {
+    #[error("The cell {0} does not exist in the library")]
+    PatternDoesNotExist(String),
+    #[error("The cell has geometry on layers that were not requested: {}", .0.iter().join(", "))]
+    UnexpectedLayers(Vec<i16>),
+    #[error("Layer {layer} has {count} zero-area regions")]
+    DegenerateRegions { layer: i16, count: usize },
+    #[error("Failed to parse the GDS library: {0}")]
+    InvalidGds(String),
+    #[error("The library's database unit of {0} m is not a positive size")]
+    InvalidUnits(f64),
+    #[error("Coordinates reach {extent} mm, which overflows the {format} coordinate format; {}", overflow_advice(.suggested))]
+    CoordinateOverflow {
+        extent: f64,
+        format: String,
+        /// `None` when even 6 integer digits, the most Gerber allows, are too few
+        suggested: Option<String>,
+    },
+    #[error("A region needs {bytes} bytes of Gerber on its own, more than the {limit} byte limit")]
+    PartTooLarge { bytes: usize, limit: usize },
+    #[error("Cell {cell} has a {kind} on layer {layer}, which cannot be converted")]
+    UnsupportedElement {
+        cell: String,
+        kind: &'static str,
+        layer: i16,
+    },
+    #[error("Cell {} places itself through {}", .0[0], .0.join(" -> "))]
+    CyclicReference(Vec<String>),
+    #[error(transparent)]
+    Gerber(#[from] GerberError),
+}
+
+impl PatternError {
+    /// The error as a JSON object with its variant name as `kind`, its message, and the
+    /// values it carries under their own keys
+    pub fn to_json(&self) -> serde_json::Value {
+        let (kind, fields) = match self {
+            Self::PatternDoesNotExist(cell) => ("PatternDoesNotExist", json!({ "cell": cell })),
+            Self::UnexpectedLayers(layers) => ("UnexpectedLayers", json!({ "layers": layers })),
+            Self::DegenerateRegions { layer, count } => (
+                "DegenerateRegions",
+                json!({ "layer": layer, "count": count }),
+            ),
+            Self::InvalidGds(detail) => ("InvalidGds", json!({ "detail": detail })),
+            Self::InvalidUnits(db_unit) => ("InvalidUnits", json!({ "db_unit": db_unit })),
+            Self::CoordinateOverflow {
+                extent,
+                format,
+                suggested,
+            } => (
+                "CoordinateOverflow",
+                json!({ "extent_mm": extent, "format": format, "suggested": suggested }),
+            ),
+            Self::PartTooLarge { bytes, limit } => {
+                ("PartTooLarge", json!({ "bytes": bytes, "limit": limit }))
+            }
+            Self::UnsupportedElement { cell, kind, layer } => (
+                "UnsupportedElement",
+                json!({ "cell": cell, "element": kind, "layer": layer }),
+            ),
+            Self::CyclicReference(cycle) => ("CyclicReference", json!({ "cycle": cycle })),
+            Self::Gerber(_) => ("Gerber", json!({})),
+        };
+        let mut value = fields;
+        value["kind"] = kind.into();
+        value["message"] = self.to_string().into();
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn keep_largest_keeps_the_biggest_regions() {
+        let lib = library([cell(
+            "TOP",
+            [1, 5, 3, 4, 2].map(|side| rect(1, (side * 100, 0), (side * 101, side))),
+        )]);
+        let mut pat = convert(&lib, "TOP", 1);
+        pat.keep_largest(2);
+        let areas = pat.regions().map(Region::signed_area).collect_vec();
+        assert_eq!(areas, [25., 16.]);
+    }
+
+    #[test]
+    fn keep_largest_breaks_ties_by_emission_order() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (2, 2)),
+                rect(1, (10, 0), (12, 2)),
+                rect(1, (20, 0), (21, 1)),
+            ],
+        )]);
+        let mut pat = convert(&lib, "TOP", 1);
+        pat.keep_largest(1);
+        assert_eq!(
+            sorted_coords(&pat),
+            [vec![(0, 0), (2, 0), (2, 2), (0, 2), (0, 0)]]
+        );
+    }
+
+    #[test]
+    fn round_endcaps_follow_the_facet_count() {
+        let lib = library([cell("TOP", [path(1, 1, 100, &[(0, 0), (1000, 0)])])]);
+        let vertices = |facets| {
+            let opts = ConvertOptions {
+                facets,
+                ..Default::default()
+            };
+            let pat = Pattern::from_gds_struct(&lib, "TOP", 1, &opts).unwrap();
+            let region = pat.regions().exactly_one().ok().unwrap();
+            assert_eq!(region.points().first(), region.points().last());
+            region.points().len() - 1
+        };
+        // Both sides of the path, plus the three interior points of each half-circle cap
+        assert_eq!(vertices(8), 2 + 3 + 2 + 3);
+        assert_eq!(vertices(32), 2 + 15 + 2 + 15);
+    }
+
+    #[test]
+    fn rotated_reference_to_a_rotated_array_composes_both_transforms() {
+        // The array steps by 100 along its own rotated column axis, and the whole cell is
+        // then turned another quarter and moved
+        let lib = library([
+            cell("LEAF", [rect(1, (0, 0), (10, 20))]),
+            cell(
+                "MID",
+                [aref(
+                    "LEAF",
+                    (2, 1),
+                    [(0, 0), (0, 200), (-50, 0)],
+                    rotated(90.),
+                )],
+            ),
+            cell("TOP", [sref("MID", (1000, 0), rotated(90.))]),
+        ]);
+        let pat = convert(&lib, "TOP", 1);
+        assert_eq!(
+            pat.regions().map(coords).collect_vec(),
+            [
+                vec![(1000, 0), (990, 0), (990, -20), (1000, -20), (1000, 0)],
+                vec![(900, 0), (890, 0), (890, -20), (900, -20), (900, 0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn cells_below_the_placeholder_depth_become_their_bounding_boxes() {
+        let lib = library([
+            cell(
+                "LEAF",
+                [rect(1, (0, 0), (10, 10)), rect(1, (20, 30), (40, 50))],
+            ),
+            cell(
+                "MID",
+                [rect(1, (0, 0), (5, 5)), sref("LEAF", (100, 0), None)],
+            ),
+            cell(
+                "TOP",
+                [rect(1, (-10, -10), (0, 0)), sref("MID", (0, 1000), None)],
+            ),
+        ]);
+        let opts = ConvertOptions {
+            placeholder_depth: Some(1),
+            ..Default::default()
+        };
+        let pat = Pattern::from_gds_struct(&lib, "TOP", 1, &opts).unwrap();
+        // TOP and MID keep their own shapes, LEAF two levels down is one rectangle
+        assert_eq!(
+            sorted_coords(&pat),
+            [
+                vec![(-10, -10), (0, -10), (0, 0), (-10, 0), (-10, -10)],
+                vec![(0, 1000), (5, 1000), (5, 1005), (0, 1005), (0, 1000)],
+                vec![
+                    (100, 1000),
+                    (140, 1000),
+                    (140, 1050),
+                    (100, 1050),
+                    (100, 1000)
+                ],
+            ]
+        );
+        assert_eq!(convert(&lib, "TOP", 1).len(), 4);
+    }
+
+    #[test]
+    fn placeholders_still_report_cycles() {
+        let lib = library([
+            cell("A", [rect(1, (0, 0), (1, 1)), sref("B", (0, 0), None)]),
+            cell("B", [sref("A", (0, 0), None)]),
+        ]);
+        let opts = ConvertOptions {
+            placeholder_depth: Some(0),
+            ..Default::default()
+        };
+        let err = Pattern::from_gds_struct(&lib, "A", 1, &opts).unwrap_err();
+        assert!(
+            matches!(&err, PatternError::CyclicReference(c) if c == &["A", "B", "A"]),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn overflow_suggests_a_format_that_fits() {
+        let lib = library([cell("TOP", [rect(1, (0, 0), (1_500_000_000, 10))])]);
+        let pat = convert(&lib, "TOP", 1);
+        let narrow = GerberOptions {
+            format: CoordinateFormat::new(2, 6),
+            ..Default::default()
+        };
+        let err = pat.write_gerber(&mut vec![], &lib, &narrow).unwrap_err();
+        let PatternError::CoordinateOverflow {
+            extent,
+            suggested: Some(suggested),
+            ..
+        } = err
+        else {
+            panic!("{err}")
+        };
+        assert_eq!(extent, 1500.);
+        assert_eq!(suggested, "4.6");
+        let format = parse_coord_format(&suggested).unwrap();
+        assert!(extent < 10f64.powi(format.integer as i32));
+        let fits = GerberOptions {
+            format,
+            ..Default::default()
+        };
+        pat.write_gerber(&mut vec![], &lib, &fits).unwrap();
+    }
+
+    #[test]
+    fn overflow_beyond_every_format_advises_recentering() {
+        let mut lib = library([cell("TOP", [rect(1, (0, 0), (1_500_000_000, 10))])]);
+        lib.units = gds21::GdsUnits::new(1e-3, 1e-6);
+        let pat = convert(&lib, "TOP", 1);
+        let err = pat
+            .write_gerber(&mut vec![], &lib, &GerberOptions::default())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PatternError::CoordinateOverflow {
+                    suggested: None,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert!(
+            err.to_string().ends_with("move the origin with --center"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn board_outline_strokes_one_contour_around_overlapping_rects() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (1000, 1000)),
+                rect(1, (500, 500), (1500, 1500)),
+                rect(1, (1400, 0), (2000, 600)),
+            ],
+        )]);
+        let outline = convert(&lib, "TOP", 1).outer_contours();
+        assert_eq!(outline.len(), 1);
+        let opts = GerberOptions {
+            outline: true,
+            ..Default::default()
+        };
+        let text = gerber(&outline, &lib, &opts);
+        assert_eq!(text.matches("D02*").count(), 1);
+        assert!(!text.contains("G36*"), "{text}");
+    }
+
+    #[test]
+    fn board_outline_drops_holes() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (30, 10)),
+                rect(1, (0, 20), (30, 30)),
+                rect(1, (0, 0), (10, 30)),
+                rect(1, (20, 0), (30, 30)),
+            ],
+        )]);
+        let outline = convert(&lib, "TOP", 1).outer_contours();
+        let areas = outline.regions().map(Region::signed_area).collect_vec();
+        assert_eq!(areas, [900.]);
+    }
+
+    #[test]
+    fn zero_database_unit_is_rejected() {
+        let mut lib = library([cell("TOP", [rect(1, (0, 0), (10, 10))])]);
+        lib.units = gds21::GdsUnits::new(1e-3, 0.);
+        let err = Pattern::from_gds_struct(&lib, "TOP", 1, &ConvertOptions::default()).unwrap_err();
+        assert!(
+            matches!(err, PatternError::InvalidUnits(u) if u == 0.),
+            "{err}"
+        );
+        for bad in [-1e-9, f64::NAN, f64::INFINITY] {
+            lib.units = gds21::GdsUnits::new(1e-3, bad);
+            assert!(matches!(
+                check_units(&lib),
+                Err(PatternError::InvalidUnits(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn bias_grows_and_shrinks_a_square() {
+        let lib = library([cell("TOP", [rect(1, (0, 0), (1000, 1000))])]);
+        let square = convert(&lib, "TOP", 1);
+        let bbox = |mm, join| {
+            let pat = square.clone().biased(mm, &lib, join, 32);
+            let (lo, hi) = pat.bbox().unwrap();
+            (pat.len(), (lo.x, lo.y), (hi.x, hi.y))
+        };
+        assert_eq!(bbox(1e-4, Join::Miter), (1, (-100, -100), (1100, 1100)));
+        assert_eq!(bbox(1e-4, Join::Round), (1, (-100, -100), (1100, 1100)));
+        assert_eq!(bbox(-1e-4, Join::Miter), (1, (100, 100), (900, 900)));
+    }
+
+    #[test]
+    fn bias_drops_collapsed_regions_with_a_warning() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (1000, 1000)),
+                rect(1, (2000, 0), (2100, 1000)),
+            ],
+        )]);
+        let pat = convert(&lib, "TOP", 1);
+        let (shrunk, lines) = capture_stderr(|| pat.biased(-1e-4, &lib, Join::Miter, 32));
+        assert_eq!(shrunk.len(), 1);
+        assert_eq!(
+            lines,
+            ["warning: Bias of -0.0001 mm collapsed 1 of 2 regions, dropping them"]
+        );
+    }
+
+    #[test]
+    fn datatype_counts_cover_the_whole_hierarchy() {
+        let tri = |datatype, x| boundary(1, datatype, &[(x, 0), (x + 10, 0), (x, 10)]);
+        let lib = library([
+            cell("SUB", [tri(2, 0), tri(2, 20), tri(1, 40), tri(0, 60)]),
+            cell(
+                "TOP",
+                [
+                    tri(0, 0),
+                    tri(1, 0),
+                    tri(2, 0),
+                    boundary(2, 5, &[(0, 0), (1, 0), (0, 1)]),
+                    sref("SUB", (0, 100), None),
+                ],
+            ),
+        ]);
+        let counts = convert(&lib, "TOP", 1).datatype_counts();
+        assert_eq!(counts, BTreeMap::from([(0, 2), (1, 2), (2, 3)]));
+    }
+
+    #[test]
+    fn rotated_array_steps_along_its_rotated_axes() {
+        // Columns 100 apart along x and rows 200 apart along y before the quarter turn,
+        // with GDS storing the corner points already rotated
+        let lib = library([
+            cell("PAD", [rect(1, (0, 0), (10, 20))]),
+            cell(
+                "TOP",
+                [aref(
+                    "PAD",
+                    (2, 2),
+                    [(1000, 1000), (1000, 1200), (600, 1000)],
+                    rotated(90.),
+                )],
+            ),
+        ]);
+        let pat = convert(&lib, "TOP", 1);
+        let boxes = pat
+            .regions()
+            .map(|r| {
+                let (lo, hi) = bounds(r.points().iter().copied()).unwrap();
+                ((lo.x, lo.y), (hi.x, hi.y))
+            })
+            .collect_vec();
+        assert_eq!(
+            boxes,
+            [
+                ((980, 1000), (1000, 1010)),
+                ((780, 1000), (800, 1010)),
+                ((980, 1100), (1000, 1110)),
+                ((780, 1100), (800, 1110)),
+            ]
+        );
+    }
+
+    #[test]
+    fn converted_layers_follow_the_conversion_filters() {
+        let lib = library([
+            cell("FILL", [rect(3, (0, 0), (1, 1))]),
+            cell(
+                "TOP",
+                [
+                    rect(1, (0, 0), (1, 1)),
+                    boundary(2, 7, &[(0, 0), (1, 0), (0, 1)]),
+                    sref("FILL", (0, 0), None),
+                ],
+            ),
+        ]);
+        let all = converted_layers(&lib, "TOP", &ConvertOptions::default()).unwrap();
+        assert_eq!(all, BTreeSet::from([1, 2, 3]));
+        let opts = ConvertOptions {
+            exclude_cells: vec!["FILL".into()],
+            datatypes: vec![0..=0],
+            ..Default::default()
+        };
+        let filtered = converted_layers(&lib, "TOP", &opts).unwrap();
+        assert_eq!(filtered, BTreeSet::from([1]));
+    }
+
+    #[test]
+    fn excluded_cells_contribute_no_regions() {
+        let lib = library([
+            cell(
+                "FILL",
+                [
+                    rect(1, (100, 100), (110, 110)),
+                    rect(1, (200, 0), (210, 10)),
+                ],
+            ),
+            cell("PAD", [rect(1, (0, 0), (5, 5))]),
+            cell(
+                "TOP",
+                [
+                    rect(1, (-50, -50), (-40, -40)),
+                    sref("FILL", (0, 0), None),
+                    sref("PAD", (1000, 0), None),
+                ],
+            ),
+        ]);
+        let whole = sorted_coords(&convert(&lib, "TOP", 1));
+        let fill = sorted_coords(&convert(&lib, "FILL", 1));
+        let opts = ConvertOptions {
+            exclude_cells: vec!["FILL".into()],
+            ..Default::default()
+        };
+        let excluded = sorted_coords(&Pattern::from_gds_struct(&lib, "TOP", 1, &opts).unwrap());
+        assert_eq!(excluded.len(), whole.len() - fill.len());
+        assert!(fill.iter().all(|region| !excluded.contains(region)));
+        assert!(excluded.iter().all(|region| whole.contains(region)));
+    }
+
+    #[test]
+    fn degenerate_regions_are_dropped_and_counted() {
+        let lib = library([cell(
+            "TOP",
+            [
+                boundary(1, 0, &[(0, 0), (10, 10), (20, 20)]),
+                rect(1, (0, 0), (10, 10)),
+            ],
+        )]);
+        let mut pat = convert(&lib, "TOP", 1);
+        assert_eq!(pat.drop_degenerate(), 1);
+        assert_eq!(
+            sorted_coords(&pat),
+            [vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)]]
+        );
+        assert_eq!(pat.drop_degenerate(), 0);
+    }
+
+    #[test]
+    fn outline_aperture_uses_the_requested_width() {
+        let lib = library([cell("TOP", [rect(1, (0, 0), (10, 10))])]);
+        let pat = convert(&lib, "TOP", 1);
+        let outline = |outline_width| {
+            let opts = GerberOptions {
+                outline: true,
+                outline_width,
+                ..Default::default()
+            };
+            gerber(&pat, &lib, &opts)
+        };
+        assert!(outline(0.25).contains("%ADD10C,0.25*%\nD10*\n"));
+        assert!(outline(0.1).contains("%ADD10C,0.1*%\nD10*\n"));
+    }
+
+    #[test]
+    fn gerber_style_only_changes_line_breaks() {
+        let lib = library([cell("TOP", [rect(1, (0, 0), (10, 10))])]);
+        let pat = convert(&lib, "TOP", 1);
+        let styled = |style| {
+            let opts = GerberOptions {
+                style,
+                ..Default::default()
+            };
+            gerber(&pat, &lib, &opts)
+        };
+        let commands = [
+            "%FSLAX66Y66*%",
+            "%MOMM*%",
+            "G36*",
+            "X0Y0D02*",
+            "X0Y0D01*",
+            "X10Y0D01*",
+            "X10Y10D01*",
+            "X0Y10D01*",
+            "X0Y0D01*",
+            "G37*",
+            "M02*",
+        ];
+        assert_eq!(
+            styled(GerberStyle::Pretty),
+            commands.map(|c| c.to_owned() + "\n").concat()
+        );
+        assert_eq!(styled(GerberStyle::Compact), commands.concat() + "\n");
+    }
+
+    #[test]
+    fn concentric_squares_alternate_dark_and_clear() {
+        // Listed innermost first, so the writer has to order them by depth itself
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (20, 20), (40, 40)),
+                rect(1, (0, 0), (60, 60)),
+                rect(1, (10, 10), (50, 50)),
+            ],
+        )]);
+        let pat = convert(&lib, "TOP", 1);
+        assert_eq!(pat.nesting_depths(), [2, 0, 1]);
+        let opts = GerberOptions {
+            nested_polarity: true,
+            ..Default::default()
+        };
+        let out = gerber(&pat, &lib, &opts);
+        let sequence = out
+            .lines()
+            .filter(|l| l.starts_with("%LP") || l.ends_with("D02*"))
+            .collect_vec();
+        assert_eq!(
+            sequence,
+            ["X0Y0D02*", "%LPC*%", "X10Y10D02*", "%LPD*%", "X20Y20D02*"]
+        );
+    }
+
+    #[test]
+    fn flashes_stay_outside_region_mode() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (10, 10)),
+                boundary(1, 0, &[(100, 0), (120, 0), (100, 30)]),
+            ],
+        )]);
+        let pat = convert(&lib, "TOP", 1);
+        let opts = GerberOptions {
+            flash_rects: true,
+            ..Default::default()
+        };
+        let out = gerber(&pat, &lib, &opts);
+        assert_eq!(
+            out.lines().collect_vec(),
+            [
+                "%FSLAX66Y66*%",
+                "%MOMM*%",
+                "%ADD11R,0.00001X0.00001*%",
+                "D11*",
+                "X5Y5D03*",
+                "G36*",
+                "X100Y0D02*",
+                "X100Y0D01*",
+                "X120Y0D01*",
+                "X100Y30D01*",
+                "X100Y0D01*",
+                "G37*",
+                "M02*",
+            ]
+        );
+    }
+
+    #[test]
+    fn fractional_magnification_warns_about_rounding() {
+        let magnified = Some(GdsStrans {
+            mag: Some(1.5),
+            ..Default::default()
+        });
+        let lib = library([
+            cell("LEAF", [rect(1, (0, 0), (1, 1))]),
+            cell("TOP", [sref("LEAF", (0, 0), magnified)]),
+        ]);
+        let (pat, stderr) = capture_stderr(|| convert(&lib, "TOP", 1));
+        assert_eq!(pat.len(), 1);
+        assert_eq!(
+            stderr,
+            ["warning: Transforms in cell TOP moved layer 1 coordinates off the database grid, rounding them by up to 0.707 database units"]
+        );
+        let (_, stderr) = capture_stderr(|| convert(&lib, "LEAF", 1));
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn sub_cell_converts_at_its_authored_coordinates() {
+        let lib = library([
+            cell("LEAF", [boundary(1, 0, &[(5, 5), (25, 5), (5, 15)])]),
+            cell("TOP", [sref("LEAF", (1000, -300), rotated(90.))]),
+        ]);
+        assert_eq!(
+            sorted_coords(&convert(&lib, "LEAF", 1)),
+            [vec![(5, 5), (25, 5), (5, 15), (5, 5)]]
+        );
+        assert_eq!(
+            sorted_coords(&convert(&lib, "TOP", 1)),
+            [vec![(995, -295), (995, -275), (985, -295), (995, -295)]]
+        );
+    }
+
+    #[test]
+    fn optimized_travel_is_shorter_and_keeps_every_region() {
+        let lib = library([cell(
+            "TOP",
+            [900, 0, 700, 200, 500, 100].map(|x| rect(1, (x, x % 300), (x + 10, x % 300 + 10))),
+        )]);
+        let travel = |pat: &Pattern| {
+            let centroids = pat.regions().map(Region::centroid).collect_vec();
+            let start = dist((0., 0.), centroids[0]);
+            start
+                + centroids
+                    .iter()
+                    .tuple_windows()
+                    .map(|(a, b)| dist(*a, *b))
+                    .sum::<f64>()
+        };
+        let scattered = convert(&lib, "TOP", 1);
+        let mut optimized = scattered.clone();
+        optimized.optimize_travel();
+        assert!(travel(&optimized) < travel(&scattered));
+        assert_eq!(sorted_coords(&optimized), sorted_coords(&scattered));
+    }
+
+    #[test]
+    fn convert_bytes_matches_converting_the_loaded_library() {
+        let lib = library([cell(
+            "TOP",
+            [rect(1, (0, 0), (10, 10)), rect(2, (5, 5), (6, 6))],
+        )]);
+        let mut gds = vec![];
+        lib.write(&mut gds).unwrap();
+        let out = convert_bytes(&gds, "TOP", 1).unwrap();
+        let expected = gerber(&convert(&lib, "TOP", 1), &lib, &GerberOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert!(matches!(
+            convert_bytes(&gds, "MISSING", 1),
+            Err(PatternError::PatternDoesNotExist(_))
+        ));
+        assert!(matches!(
+            convert_bytes(&gds[..gds.len() / 2], "TOP", 1),
+            Err(PatternError::InvalidGds(_))
+        ));
+    }
+
+    #[test]
+    fn inverted_output_clears_the_shapes_from_a_dark_field() {
+        let lib = library([cell(
+            "TOP",
+            [rect(1, (0, 0), (10, 10)), rect(1, (20, 0), (30, 40))],
+        )]);
+        let pat = convert(&lib, "TOP", 1);
+        let opts = GerberOptions {
+            invert: true,
+            ..Default::default()
+        };
+        let out = gerber(&pat, &lib, &opts);
+        let sequence = out
+            .lines()
+            .filter(|l| l.starts_with("%LP") || l.ends_with("D02*"))
+            .collect_vec();
+        // The field spans the bounding box of both shapes
+        assert_eq!(
+            sequence,
+            ["X0Y0D02*", "%LPC*%", "X0Y0D02*", "X20Y0D02*", "%LPD*%"]
+        );
+        assert!(out.contains("X30Y40D01*\nX0Y40D01*"));
+    }
+
+    #[test]
+    fn element_counts_tally_each_kind_per_layer() {
+        use gds21::{GdsBox, GdsTextElem};
+
+        let lib = library([
+            cell("LEAF", [rect(1, (0, 0), (1, 1))]),
+            cell(
+                "TOP",
+                [
+                    rect(1, (0, 0), (1, 1)),
+                    rect(1, (2, 0), (3, 1)),
+                    path(1, 0, 10, &[(0, 0), (100, 0)]),
+                    path(2, 0, 10, &[(0, 0), (100, 0)]),
+                    GdsBox {
+                        layer: 2,
+                        ..Default::default()
+                    }
+                    .into(),
+                    GdsTextElem {
+                        string: "label".into(),
+                        layer: 3,
+                        ..Default::default()
+                    }
+                    .into(),
+                    sref("LEAF", (0, 0), None),
+                    sref("LEAF", (10, 0), None),
+                    aref("LEAF", (2, 2), [(0, 0), (20, 0), (0, 20)], None),
+                ],
+            ),
+        ]);
+        let counts = element_counts(find_struct(&lib, "TOP").unwrap());
+        assert_eq!((counts.refs, counts.arefs), (2, 1));
+        let layers = (counts.layers.iter())
+            .map(|(&l, c)| (l, c.boundaries, c.paths, c.boxes, c.texts))
+            .collect_vec();
+        assert_eq!(layers, [(1, 2, 1, 0, 0), (2, 0, 1, 1, 0), (3, 0, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn datatype_range_keeps_only_its_datatypes() {
+        let lib = library([cell(
+            "TOP",
+            (0..=4).map(|dt| {
+                let x = 10 * dt as i32;
+                boundary(1, dt, &[(x, 0), (x + 5, 0), (x, 5)])
+            }),
+        )]);
+        let opts = ConvertOptions {
+            datatypes: vec![parse_datatype_range("1-3").unwrap()],
+            ..Default::default()
+        };
+        let pat = Pattern::from_gds_struct(&lib, "TOP", 1, &opts).unwrap();
+        assert_eq!(
+            pat.datatype_counts(),
+            BTreeMap::from([(1, 1), (2, 1), (3, 1)])
+        );
+        assert!(parse_datatype_range("3-1").is_err());
+    }
+
+    #[test]
+    fn bbox_follows_rotation_and_reflection() {
+        let reflected = Some(GdsStrans {
+            reflected: true,
+            angle: Some(90.),
+            ..Default::default()
+        });
+        let lib = library([
+            cell("LEAF", [rect(1, (0, 0), (30, 10))]),
+            cell("TOP", [sref("LEAF", (100, 100), rotated(90.))]),
+            cell("FLIP", [sref("LEAF", (100, 100), reflected)]),
+        ]);
+        let corners = |(lo, hi): (Point, Point)| ((lo.x, lo.y), (hi.x, hi.y));
+        let bbox = |name| corners(convert(&lib, name, 1).bbox().unwrap());
+        assert_eq!(bbox("TOP"), ((90, 100), (100, 130)));
+        assert_eq!(bbox("FLIP"), ((100, 100), (110, 130)));
+    }
+
+    #[test]
+    fn image_polarity_is_declared_in_the_header() {
+        let lib = library([cell("TOP", [rect(1, (0, 0), (10, 10))])]);
+        let pat = convert(&lib, "TOP", 1);
+        let header = |image_polarity| {
+            let opts = GerberOptions {
+                image_polarity,
+                ..Default::default()
+            };
+            let out = gerber(&pat, &lib, &opts);
+            out.lines().take_while(|l| l.starts_with('%')).join("\n")
+        };
+        assert_eq!(
+            header(Some(ImagePolarity::Negative)),
+            "%FSLAX66Y66*%\n%MOMM*%\n%IPNEG*%\n%TF.FilePolarity,Negative*%"
+        );
+        assert_eq!(
+            header(Some(ImagePolarity::Positive)),
+            "%FSLAX66Y66*%\n%MOMM*%\n%IPPOS*%\n%TF.FilePolarity,Positive*%"
+        );
+        assert_eq!(header(None), "%FSLAX66Y66*%\n%MOMM*%");
+    }
+
+    #[test]
+    fn fine_database_units_warn_about_lost_precision() {
+        let mut lib = library([]);
+        let warnings =
+            |lib: &GdsLibrary, format| capture_stderr(|| warn_precision_loss(lib, format)).1;
+        assert!(warnings(&lib, CoordinateFormat::new(6, 6)).is_empty());
+        assert_eq!(
+            warnings(&lib, CoordinateFormat::new(4, 5)),
+            ["warning: The database unit of 0.000000001 m is finer than the 4.5 format's resolution of 0.00001 mm, features below it will be lost; use --coord-format 4.6"]
+        );
+        lib.units = gds21::GdsUnits::new(1e-4, 1e-10);
+        assert_eq!(
+            warnings(&lib, CoordinateFormat::new(6, 6)),
+            ["warning: The database unit of 0.0000000001 m is finer than the 6.6 format's resolution of 0.000001 mm, features below it will be lost; Gerber cannot go finer than 6 decimals, so coarsen the design's units"]
+        );
+    }
+
+    #[test]
+    fn pruning_skips_cells_without_the_layer_but_keeps_the_regions() {
+        let lib = library([
+            cell("PAD", [rect(1, (0, 0), (10, 10))]),
+            cell("FILL", [rect(2, (0, 0), (10, 10))]),
+            cell(
+                "BLOCK",
+                [sref("FILL", (0, 0), None), sref("FILL", (20, 0), None)],
+            ),
+            cell(
+                "TOP",
+                [
+                    sref("PAD", (0, 0), None),
+                    sref("BLOCK", (100, 0), None),
+                    aref("BLOCK", (3, 1), [(0, 100), (300, 100), (0, 200)], None),
+                    aref("PAD", (2, 1), [(0, 500), (200, 500), (0, 600)], None),
+                ],
+            ),
+        ]);
+        let opts = ConvertOptions::default();
+        let flatten = |index| Pattern::counting_visits(&lib, &index, "TOP", 1, &opts).unwrap();
+        let (pruned, pruned_visits) = flatten(CellIndex::new(&lib));
+        let (full, full_visits) = flatten(CellIndex::default());
+        assert_eq!(sorted_coords(&pruned), sorted_coords(&full));
+        assert_eq!(pruned.len(), 3);
+        // TOP and both PAD placements, against every BLOCK and FILL as well
+        assert_eq!((pruned_visits, full_visits), (3, 9));
+    }
+
+    #[test]
+    fn split_parts_are_whole_files_that_add_up_to_the_layer() {
+        let lib = library([cell(
+            "TOP",
+            (0..20).map(|i| rect(1, (i * 100, 0), (i * 100 + 50, 50))),
+        )]);
+        let pat = convert(&lib, "TOP", 1);
+        let opts = GerberOptions::default();
+        let parts = pat.split_to_fit(&lib, &opts, 400).unwrap();
+        assert!(parts.len() > 1);
+        let mut regions = vec![];
+        for part in &parts {
+            let out = gerber(part, &lib, &opts);
+            assert!(out.len() <= 400, "{out}");
+            assert!(out.starts_with("%FSLAX66Y66*%\n%MOMM*%\n"));
+            assert!(out.ends_with("G37*\nM02*\n"));
+            regions.extend(part.regions().map(coords));
+        }
+        assert_eq!(regions, pat.regions().map(coords).collect_vec());
+        assert_eq!(pat.split_to_fit(&lib, &opts, usize::MAX).unwrap().len(), 1);
+        assert!(matches!(
+            pat.split_to_fit(&lib, &opts, 50),
+            Err(PatternError::PartTooLarge { limit: 50, .. })
+        ));
+    }
+
+    #[test]
+    fn cyclic_reference_serializes_its_kind_and_chain() {
+        let lib = library([
+            cell("TOP", [sref("A", (0, 0), None)]),
+            cell("A", [rect(1, (0, 0), (1, 1)), sref("B", (0, 0), None)]),
+            cell("B", [sref("A", (5, 0), None)]),
+        ]);
+        let err = Pattern::from_gds_struct(&lib, "TOP", 1, &ConvertOptions::default()).unwrap_err();
+        let value = err.to_json();
+        assert_eq!(value["kind"], "CyclicReference");
+        assert_eq!(value["cycle"], json!(["A", "B", "A"]));
+        assert_eq!(value["message"], err.to_string());
+    }
+
+    #[test]
+    fn flat_cell_fast_path_matches_the_indexed_path() {
+        let lib = library([
+            cell(
+                "FLAT",
+                [
+                    rect(1, (0, 0), (10, 10)),
+                    boundary(1, 3, &[(20, 0), (30, 0), (20, 10)]),
+                    path(1, 2, 4, &[(0, 50), (100, 50), (100, 90)]),
+                    rect(2, (0, 0), (5, 5)),
+                ],
+            ),
+            cell("OTHER", [sref("FLAT", (0, 0), None)]),
+        ]);
+        let opts = ConvertOptions::default();
+        let index = CellIndex::new(&lib);
+        for layer in [1, 2, 3] {
+            let fast = Pattern::from_gds_struct(&lib, "FLAT", layer, &opts).unwrap();
+            let general = Pattern::from_indexed(&lib, &index, "FLAT", layer, &opts).unwrap();
+            assert_eq!(
+                fast.regions().map(coords).collect_vec(),
+                general.regions().map(coords).collect_vec()
+            );
+            assert_eq!(fast.datatype_counts(), general.datatype_counts());
+        }
+    }
+}
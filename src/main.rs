@@ -1,159 +1,2007 @@
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::{BufWriter, Write},
-    path::PathBuf,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use gds21::{GdsElement, GdsLibrary, GdsPoint, GdsStructRef};
-use gerber_types::{CoordinateNumber, GerberResult};
-use thiserror::Error;
+use gdsii_to_gerber::{
+    cell_layers, check_units, combined_bbox, converted_layers, element_counts, info,
+    library_layers, load_subtree, missing_refs, parse_coord_format, parse_datatype_range,
+    set_verbosity, user_units_per_mm, validate, warn, warn_precision_loss, write_svg_stack,
+    ApertureShape, CellIndex, ConvertOptions, GerberOptions, GerberStyle, ImagePolarity, Join,
+    Pattern, PatternDiff, PatternError, Point, Region, Verbosity,
+};
+use gerber_types::CoordinateFormat;
+use itertools::Itertools;
+use serde_json::json;
+
+mod config;
+mod manifest;
+mod summary;
+
+use config::{Config, LayerList, LayerStyle};
 
 #[derive(Parser, Debug)]
 struct Args {
     /// File path of gdsii file
     path: PathBuf,
-    /// Name of the cell to generate files for
-    cell: String,
-    /// Layers to generate files for
-    #[arg(default_value = "1")]
+    /// Layers to generate files for, overriding the config's list [default: 1]
     layers: Vec<i16>,
+    /// Name of the cell to generate files for
+    #[arg(long)]
+    cell: Option<String>,
+    /// Labels used in place of layer numbers in output names and SVG colors, only
+    /// settable from the config
+    #[arg(skip)]
+    layer_styles: BTreeMap<i16, LayerStyle>,
+    /// TOML file providing defaults for any of the options below
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Report the datatypes present on each layer of the cell, with region counts, instead of converting
+    #[arg(long)]
+    list_datatypes: bool,
+    /// Report how many elements of each kind every cell (or just the given cell) holds per layer, instead of converting
+    #[arg(long)]
+    count_only: bool,
+    /// Check the cell's hierarchy for problems and list them, instead of converting; exits
+    /// with an error if any are found
+    #[arg(long, conflicts_with = "count_only")]
+    validate: bool,
+    /// Compare each layer of the cell with the same layer of this cell and report added,
+    /// removed and changed regions, instead of converting; exits with an error if any differ
+    #[arg(long, value_name = "CELL", conflicts_with_all = ["count_only", "validate"])]
+    diff: Option<String>,
+    /// Library holding the --diff cell, if not the input library
+    #[arg(long, value_name = "PATH", requires = "diff")]
+    diff_file: Option<PathBuf>,
+    /// Vertices moved by at most this much still count as equal in --diff, in millimeters
+    /// [default: 0]
+    #[arg(long, value_name = "MM", requires = "diff")]
+    diff_tolerance: Option<f64>,
+    /// Convert only elements with this datatype, may be repeated
+    #[arg(long, value_name = "N")]
+    datatype: Vec<i16>,
+    /// Convert only elements with a datatype in this inclusive range, may be repeated
+    #[arg(long, value_name = "A-B", value_parser = parse_datatype_range)]
+    datatype_range: Vec<RangeInclusive<i16>>,
+    /// Fail on problems such as zero-area regions instead of dropping them with a warning
+    #[arg(long)]
+    strict: bool,
+    /// Fail if the cell has geometry on any layer that was not requested
+    #[arg(long)]
+    expect_layers: bool,
+    /// Keep coordinates relative to the cell's own origin as authored, or with `=center` move the origin to the center of the bounding box of all converted layers
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "authored")]
+    local_origin: Option<LocalOrigin>,
+    /// Move the origin to the center of the bounding box of all converted layers, same as --local-origin=center
+    #[arg(long, conflicts_with = "local_origin")]
+    center: bool,
+    /// Move the origin to the centroid of the single marker drawn on this layer
+    #[arg(long, value_name = "LAYER", conflicts_with_all = ["local_origin", "center"])]
+    origin_from_layer: Option<i16>,
+    /// With --origin-from-layer, use the first of several disconnected markers instead of failing
+    #[arg(long, requires = "origin_from_layer")]
+    origin_first: bool,
+    /// Clip every layer to the window from X0,Y0 to X1,Y1, in millimeters
+    #[arg(long, value_name = "X0,Y0,X1,Y1", value_parser = parse_crop)]
+    crop: Option<[f64; 4]>,
+    /// Clip every layer to the single rectangle drawn on this layer
+    #[arg(long, value_name = "LAYER", conflicts_with = "crop")]
+    crop_from_layer: Option<i16>,
+    /// Repeat the converted cell on a grid of COLS x ROWS copies spaced DX by DY millimeters apart
+    #[arg(long, value_name = "COLS,ROWS,DX,DY", value_parser = parse_panel)]
+    panel: Option<Panel>,
+    /// Reorder regions nearest-neighbor by centroid to shorten plotter travel
+    #[arg(long)]
+    optimize_travel: bool,
+    /// Keep only the N largest regions (by area) on each layer
+    #[arg(long, value_name = "N")]
+    keep_largest: Option<usize>,
+    /// Number of facets used to approximate a full circle wherever curves are polygonized [default: 32]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(4..))]
+    facets: Option<u32>,
+    /// Output format: gerber or geojson [default: gerber]
+    #[arg(long)]
+    format: Option<OutputFormat>,
+    /// Add this to layer numbers in output names and X2 file function attributes, leaving the geometry alone; layers labelled in the config keep their own number
+    #[arg(long, value_name = "N", allow_negative_numbers = true)]
+    layer_offset: Option<i32>,
+    /// Output file name template, `{file}`, `{cell}` and `{layer}` are substituted [default: {file}_{cell}_{layer}.g, or .geojson for GeoJSON]
+    #[arg(long)]
+    output: Option<String>,
+    /// Gerber coordinate format as integer.decimal digits [default: 6.6]
+    #[arg(long, value_parser = parse_coord_format)]
+    coord_format: Option<CoordinateFormat>,
+    /// Replace cells nested deeper than N levels with bounding-box placeholders
+    #[arg(long, value_name = "N")]
+    placeholder_depth: Option<u32>,
+    /// Grow every region outward by this many millimeters, or shrink it if negative
+    #[arg(long, value_name = "MM", allow_negative_numbers = true)]
+    bias: Option<f64>,
+    /// Corner style for --bias: miter, round or bevel [default: miter]
+    #[arg(long)]
+    bias_join: Option<Join>,
+    /// Also write each layer grown by this many millimeters as a solder mask, named with `{layer}_mask` as its layer
+    #[arg(long, value_name = "MM")]
+    mask_expand: Option<f64>,
+    /// Also write the bounding box of all converted layers as an outline file, named with `bbox` as its layer
+    #[arg(long)]
+    add_bbox_outline: bool,
+    /// Skip references to this cell, may be repeated
+    #[arg(long, value_name = "CELL")]
+    exclude_cell: Vec<String>,
+    /// Cut regions that lie inside other regions out as holes, alternating dark and clear by nesting depth
+    #[arg(long)]
+    holes: bool,
+    /// Flash axis-aligned rectangles with rectangular apertures instead of filling them as regions
+    #[arg(long)]
+    flash_rects: bool,
+    /// Write the complement of each layer: shapes cleared out of a dark field covering the bounding box (unlike --holes, which sets polarity per region)
+    #[arg(long)]
+    invert: bool,
+    /// Warn about and skip references to cells missing from the library instead of failing
+    #[arg(long)]
+    allow_missing_refs: bool,
+    /// Declare the image polarity in each file's header: pos or neg
+    #[arg(long)]
+    image_polarity: Option<ImagePolarity>,
+    /// Emit only the outer contours of each layer's union as stroked outlines
+    #[arg(long)]
+    board_outline: bool,
+    /// Aperture diameter used to stroke outlines, in millimeters [default: 0.1]
+    #[arg(long, value_name = "MM")]
+    outline_width: Option<f64>,
+    /// Gerber text layout: pretty (one command per line) or compact [default: pretty]
+    #[arg(long)]
+    gerber_style: Option<GerberStyle>,
+    /// Also render every layer into one colored SVG overview at this path
+    #[arg(long, value_name = "PATH")]
+    svg_all: Option<PathBuf>,
+    /// Split any layer whose Gerber would exceed N bytes across numbered `_partK` files
+    #[arg(long, value_name = "N", conflicts_with = "invert")]
+    max_file_bytes: Option<usize>,
+    /// Write output files on up to N threads [default: 1]
+    #[arg(long, short, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+    /// Write a listing of the apertures each output file defines, with their shapes and usage counts
+    #[arg(long, value_name = "PATH")]
+    aperture_report: Option<PathBuf>,
+    /// Write one CSV row per region with its layer, datatype, centroid, area and vertex count, in millimeters
+    #[arg(long, value_name = "PATH")]
+    csv: Option<PathBuf>,
+    /// Show lengths in reports in the library's user units instead of millimeters
+    #[arg(long)]
+    report_user_units: bool,
+    /// Write sizes and SHA-256 digests of all produced files here (JSON if it ends in .json)
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<PathBuf>,
+    /// Write a JSON summary of every output, including the GDS properties of its regions
+    #[arg(long, value_name = "PATH")]
+    summary: Option<PathBuf>,
+    /// Print errors as plain text or as a JSON object on stderr [default: human]
+    #[arg(long, value_name = "FORMAT")]
+    error_format: Option<ErrorFormat>,
+    /// Read only the cell and the cells it places from the file instead of loading the whole library
+    #[arg(long)]
+    stream: bool,
+    /// Report progress and statistics on stderr
+    #[arg(long, short, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Suppress everything on stderr except hard errors
+    #[arg(long, short)]
+    quiet: bool,
+}
+
+impl Args {
+    /// Fills in every option the command line left unset from the config file
+    fn apply_config(&mut self, config: Config) -> Result<()> {
+        self.cell = self.cell.take().or(config.cell);
+        let layers = config.layers.map(LayerList::resolve).transpose()?;
+        let layers = layers.unwrap_or_default();
+        if self.layers.is_empty() {
+            self.layers = layers.iter().map(|&(layer, _)| layer).collect();
+        }
+        self.layer_styles = layers.into_iter().collect();
+        // The origin options exclude one another, so any of them on the command line
+        // replaces all of the config's
+        if !self.center && self.local_origin.is_none() && self.origin_from_layer.is_none() {
+            self.center = config.center.unwrap_or(false);
+            self.local_origin = config
+                .local_origin
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+            self.origin_from_layer = config.origin_from_layer;
+        }
+        if self.crop.is_none() && self.crop_from_layer.is_none() {
+            self.crop = config
+                .crop
+                .as_deref()
+                .map(parse_crop)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+            self.crop_from_layer = config.crop_from_layer;
+        }
+        if self.panel.is_none() {
+            self.panel = config
+                .panel
+                .as_deref()
+                .map(parse_panel)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.optimize_travel |= config.optimize_travel.unwrap_or(false);
+        if self.datatype.is_empty() {
+            self.datatype = config.datatype.unwrap_or_default();
+        }
+        if self.datatype_range.is_empty() {
+            self.datatype_range = config
+                .datatype_range
+                .unwrap_or_default()
+                .iter()
+                .map(|s| parse_datatype_range(s))
+                .collect::<Result<_, _>>()
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.keep_largest = self.keep_largest.or(config.keep_largest);
+        self.facets = self.facets.or(config.facets);
+        if self.facets.is_some_and(|f| f < 4) {
+            bail!("facets must be at least 4");
+        }
+        if self.format.is_none() {
+            self.format = config
+                .format
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.layer_offset = self.layer_offset.or(config.layer_offset);
+        self.output = self.output.take().or(config.output);
+        if self.coord_format.is_none() {
+            self.coord_format = config
+                .coord_format
+                .as_deref()
+                .map(parse_coord_format)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.placeholder_depth = self.placeholder_depth.or(config.placeholder_depth);
+        self.manifest = self.manifest.take().or(config.manifest);
+        self.stream |= config.stream.unwrap_or(false);
+        self.csv = self.csv.take().or(config.csv);
+        self.summary = self.summary.take().or(config.summary);
+        self.report_user_units |= config.report_user_units.unwrap_or(false);
+        self.aperture_report = self.aperture_report.take().or(config.aperture_report);
+        self.jobs = self.jobs.or(config.jobs);
+        self.svg_all = self.svg_all.take().or(config.svg_all);
+        self.max_file_bytes = self.max_file_bytes.or(config.max_file_bytes);
+        self.origin_first |= config.origin_first.unwrap_or(false);
+        self.bias = self.bias.or(config.bias);
+        self.mask_expand = self.mask_expand.or(config.mask_expand);
+        if self.bias_join.is_none() {
+            self.bias_join = config
+                .bias_join
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.strict |= config.strict.unwrap_or(false);
+        self.expect_layers |= config.expect_layers.unwrap_or(false);
+        if self.exclude_cell.is_empty() {
+            self.exclude_cell = config.exclude_cell.unwrap_or_default();
+        }
+        self.add_bbox_outline |= config.add_bbox_outline.unwrap_or(false);
+        self.board_outline |= config.board_outline.unwrap_or(false);
+        self.allow_missing_refs |= config.allow_missing_refs.unwrap_or(false);
+        self.holes |= config.holes.unwrap_or(false);
+        self.flash_rects |= config.flash_rects.unwrap_or(false);
+        self.invert |= config.invert.unwrap_or(false);
+        if self.image_polarity.is_none() {
+            self.image_polarity = config
+                .image_polarity
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.outline_width = self.outline_width.or(config.outline_width);
+        if self.gerber_style.is_none() {
+            self.gerber_style = config
+                .gerber_style
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(anyhow::Error::msg)?;
+        }
+        if !self.verbose && !self.quiet {
+            self.verbose = config.verbose.unwrap_or(false);
+            self.quiet = config.quiet.unwrap_or(false);
+        }
+        if self.verbose && self.quiet {
+            bail!("verbose and quiet cannot both be set");
+        }
+        Ok(())
+    }
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    let error_format = args.error_format.unwrap_or(ErrorFormat::Human);
+    let result = run(args);
+    if let (Err(err), ErrorFormat::Json) = (&result, error_format) {
+        let value = match err.downcast_ref::<PatternError>() {
+            Some(err) => err.to_json(),
+            None => json!({ "kind": "Other", "message": format!("{err:#}") }),
+        };
+        eprintln!("{value}");
+        process::exit(1);
+    }
+    result
+}
+
+fn run(mut args: Args) -> Result<()> {
+    if let Some(path) = &args.config {
+        let config = Config::load(path)?;
+        args.apply_config(config)?;
+    }
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+    set_verbosity(level);
+    if args.outline_width.is_some_and(|w| w <= 0. || w.is_nan()) {
+        bail!("outline width must be positive");
+    }
+    if args.mask_expand.is_some_and(|e| e <= 0. || e.is_nan()) {
+        bail!("mask expansion must be positive");
+    }
+    if args.layers.is_empty() {
+        args.layers = vec![1];
+    }
     let path = args.path;
     let filename = path.file_stem().unwrap().to_str().unwrap();
-    let lib = gds21::GdsLibrary::load(&path).unwrap();
-    let cell = args.cell;
-    for layer in args.layers {
-        let pat = Pattern::from_gds_struct(&lib, &cell, layer)?;
-        let mut w = BufWriter::new(File::create(format!("{filename}_{cell}_{layer}.g",))?);
-        pat.write_gerber(&mut w, &lib)?;
+    let lib = match &args.cell {
+        // A --diff cell from the same file has to come along, as nothing else is loaded
+        Some(cell) if args.stream => match &args.diff {
+            Some(other) if args.diff_file.is_none() => load_subtree(&path, &[cell, other])?,
+            _ => load_subtree(&path, &[cell])?,
+        },
+        None if args.stream => bail!("--stream needs a cell to load"),
+        _ => gds21::GdsLibrary::load(&path).map_err(|e| PatternError::InvalidGds(e.to_string()))?,
+    };
+    check_units(&lib)?;
+    if args.count_only {
+        if let Some(cell) = &args.cell {
+            if !lib.structs.iter().any(|s| s.name == *cell) {
+                return Err(PatternError::PatternDoesNotExist(cell.clone()).into());
+            }
+        }
+        for struc in &lib.structs {
+            if args.cell.as_ref().is_some_and(|c| *c != struc.name) {
+                continue;
+            }
+            let counts = element_counts(struc);
+            println!("cell {}", struc.name);
+            println!("  refs: {}, arefs: {}", counts.refs, counts.arefs);
+            for (layer, c) in &counts.layers {
+                println!(
+                    "  layer {layer}: {} boundaries, {} paths, {} boxes, {} texts",
+                    c.boundaries, c.paths, c.boxes, c.texts
+                );
+            }
+        }
+        return Ok(());
+    }
+    let cell = args
+        .cell
+        .context("No cell given on the command line or in the config")?;
+    if args.validate {
+        let format = args.coord_format.unwrap_or(CoordinateFormat::new(6, 6));
+        let problems = validate(&lib, &cell, format)?;
+        for problem in &problems {
+            println!("{problem}");
+        }
+        if !problems.is_empty() {
+            bail!("Found {} problems in cell {cell}", problems.len());
+        }
+        info!("No problems found in cell {cell}");
+        return Ok(());
+    }
+    let index = CellIndex::new(&lib);
+    let format = args.format.unwrap_or(OutputFormat::Gerber);
+    let template = args.output.unwrap_or_else(|| match format {
+        OutputFormat::Gerber => "{file}_{cell}_{layer}.g".into(),
+        OutputFormat::Geojson => "{file}_{cell}_{layer}.geojson".into(),
+    });
+    let opts = ConvertOptions {
+        facets: args.facets.unwrap_or(32),
+        placeholder_depth: args.placeholder_depth,
+        exclude_cells: args.exclude_cell,
+        allow_missing_refs: args.allow_missing_refs,
+        datatypes: args
+            .datatype
+            .iter()
+            .map(|&d| d..=d)
+            .chain(args.datatype_range)
+            .collect(),
+    };
+    let gerber_opts = GerberOptions {
+        format: args.coord_format.unwrap_or(CoordinateFormat::new(6, 6)),
+        outline: args.board_outline,
+        outline_width: args.outline_width.unwrap_or(0.1),
+        style: args.gerber_style.unwrap_or(GerberStyle::Pretty),
+        nested_polarity: args.holes,
+        flash_rects: args.flash_rects,
+        invert: args.invert,
+        image_polarity: args.image_polarity,
+        file_function: None,
+    };
+    if format == OutputFormat::Gerber {
+        warn_precision_loss(&lib, gerber_opts.format);
+    }
+    if args.expect_layers {
+        // The marker and crop layers are read too, so they count as requested
+        let requested = (args.layers.iter().copied())
+            .chain(args.origin_from_layer)
+            .chain(args.crop_from_layer)
+            .collect_vec();
+        let unexpected = converted_layers(&lib, &cell, &opts)?
+            .into_iter()
+            .filter(|l| !requested.contains(l))
+            .collect_vec();
+        if !unexpected.is_empty() {
+            return Err(PatternError::UnexpectedLayers(unexpected).into());
+        }
+    }
+    if args.list_datatypes {
+        for &layer in &args.layers {
+            let pat = Pattern::from_indexed(&lib, &index, &cell, layer, &opts)?;
+            println!("layer {layer}");
+            for (datatype, count) in pat.datatype_counts() {
+                println!("  datatype {datatype}: {count} regions");
+            }
+        }
+        return Ok(());
+    }
+    if let Some(other_cell) = &args.diff {
+        let loaded;
+        let (other_lib, other_index) = match &args.diff_file {
+            Some(path) => {
+                let other = if args.stream {
+                    load_subtree(path, &[other_cell])?
+                } else {
+                    gds21::GdsLibrary::load(path)
+                        .map_err(|e| PatternError::InvalidGds(e.to_string()))?
+                };
+                loaded = (CellIndex::new(&other), other);
+                (&loaded.1, &loaded.0)
+            }
+            None => (&lib, &index),
+        };
+        if other_lib.units.db_unit() != lib.units.db_unit() {
+            bail!("Cannot compare libraries with different database units");
+        }
+        let tolerance = Point::from_mm((args.diff_tolerance.unwrap_or(0.), 0.), &lib).x;
+        let mut differs = false;
+        for &layer in &args.layers {
+            let old = Pattern::from_indexed(&lib, &index, &cell, layer, &opts)?;
+            let new = Pattern::from_indexed(other_lib, other_index, other_cell, layer, &opts)?;
+            let diff = old.diff(&new, tolerance);
+            println!("{}", diff_line(layer, &diff, &lib));
+            differs |= !diff.is_empty();
+        }
+        if differs {
+            bail!("Cells {cell} and {other_cell} differ");
+        }
+        return Ok(());
+    }
+    let layer_offset = args.layer_offset.unwrap_or(0);
+    let outputs = args
+        .layers
+        .iter()
+        .map(|&layer| {
+            // A label names the layer outright, so the offset renumbers neither its file
+            // nor its attribute
+            let label = args.layer_styles.get(&layer).and_then(|s| s.label.as_ref());
+            let (number, label) = match label {
+                Some(label) => (layer as i32, label.clone()),
+                None => {
+                    let number = layer as i32 + layer_offset;
+                    (number, number.to_string())
+                }
+            };
+            let out = output_path(&template, filename, &cell, &label);
+            let mask_out = args
+                .mask_expand
+                .map(|_| output_path(&template, filename, &cell, &format!("{label}_mask")));
+            (layer, number, out, mask_out)
+        })
+        .collect_vec();
+    let bbox_out = args
+        .add_bbox_outline
+        .then(|| output_path(&template, filename, &cell, "bbox"));
+    if args.crop.is_some() && args.crop_from_layer.is_some() {
+        bail!("--crop and --crop-from-layer cannot both be set");
+    }
+    let crop = match (args.crop, args.crop_from_layer) {
+        (Some([x0, y0, x1, y1]), _) => Some((
+            Point::from_mm((x0.min(x1), y0.min(y1)), &lib),
+            Point::from_mm((x0.max(x1), y0.max(y1)), &lib),
+        )),
+        (None, Some(crop_layer)) => {
+            let window = Pattern::from_indexed(&lib, &index, &cell, crop_layer, &opts)?;
+            match window.regions().map(Region::as_rect).collect_vec()[..] {
+                [Some(rect)] => Some(rect),
+                _ => bail!(
+                    "Layer {crop_layer} of cell {cell} must hold exactly one rectangle to crop to, found {} regions",
+                    window.len()
+                ),
+            }
+        }
+        (None, None) => None,
+    };
+    let library_layers = library_layers(&lib);
+    let cell_layers = cell_layers(&lib, &cell)?;
+    let mut jobs = vec![];
+    for (layer, number, out, mask_out) in outputs {
+        let gerber = GerberOptions {
+            file_function: Some(format!("Other,L{number}")),
+            ..gerber_opts.clone()
+        };
+        let mut pat = Pattern::from_indexed(&lib, &index, &cell, layer, &opts)?;
+        if let Some(bias) = args.bias {
+            let join = args.bias_join.unwrap_or(Join::Miter);
+            pat = pat.biased(bias, &lib, join, opts.facets);
+        }
+        if let Some(window) = crop {
+            pat = pat.cropped(window);
+        }
+        match pat.drop_degenerate() {
+            0 => {}
+            count if args.strict => {
+                return Err(PatternError::DegenerateRegions { layer, count }.into())
+            }
+            count => warn!("Dropped {count} zero-area regions on layer {layer}"),
+        }
+        if let Some(n) = args.keep_largest {
+            pat.keep_largest(n);
+        }
+        if args.board_outline {
+            pat = pat.outer_contours();
+        }
+        if let Some(panel) = &args.panel {
+            let single = pat.clone();
+            for (col, row) in (0..panel.cols).cartesian_product(0..panel.rows).skip(1) {
+                let offset = (col as f64 * panel.dx, row as f64 * panel.dy);
+                pat.merge(single.translated(Point::from_mm(offset, &lib)));
+            }
+        }
+        if args.optimize_travel {
+            pat.optimize_travel();
+        }
+        if pat.is_empty() {
+            if args.stream && !cell_layers.contains(&layer) {
+                // Only the cell's own subtree was loaded, so the rest of the library is unknown
+                warn!("Layer {layer} is not present in cell {cell}");
+            } else if !library_layers.contains(&layer) {
+                warn!("Layer {layer} is not present anywhere in the library");
+            } else if !cell_layers.contains(&layer) {
+                warn!("Layer {layer} is present elsewhere in the library but not in cell {cell}");
+            } else {
+                warn!("Layer {layer} has no geometry in cell {cell}");
+            }
+        }
+        let mask = args.mask_expand.zip(mask_out).map(|(expand, out)| {
+            let join = args.bias_join.unwrap_or(Join::Miter);
+            Job {
+                layer: None,
+                pattern: pat.clone().biased(expand, &lib, join, opts.facets),
+                out,
+                gerber: GerberOptions {
+                    file_function: Some(format!("Other,L{number}-mask")),
+                    ..gerber.clone()
+                },
+            }
+        });
+        jobs.push(Job {
+            layer: Some(layer),
+            pattern: pat,
+            out,
+            gerber,
+        });
+        jobs.extend(mask);
+    }
+    if args.center {
+        args.local_origin = Some(LocalOrigin::Center);
+    }
+    if args.local_origin == Some(LocalOrigin::Center) {
+        if let Some((lo, hi)) = combined_bbox(layer_patterns(&jobs)) {
+            let shift = Point {
+                x: -(lo.x + (hi.x - lo.x) / 2),
+                y: -(lo.y + (hi.y - lo.y) / 2),
+            };
+            for job in &mut jobs {
+                job.pattern += shift;
+            }
+        }
+    }
+    if let Some(marker) = args.origin_from_layer {
+        if args.local_origin.is_some() {
+            bail!("--origin-from-layer cannot be combined with --local-origin or --center");
+        }
+        let markers = Pattern::from_indexed(&lib, &index, &cell, marker, &opts)?.outer_contours();
+        let first = match markers.regions().next() {
+            None => bail!("Cell {cell} has no origin marker on layer {marker}"),
+            Some(_) if markers.len() > 1 && !args.origin_first => bail!(
+                "Cell {cell} has {} disconnected origin markers on layer {marker}; pass --origin-first to use the first",
+                markers.len()
+            ),
+            Some(first) => first,
+        };
+        let (x, y) = first.centroid();
+        let shift = Point::from((-x, -y));
+        info!("Moving the origin to the marker on layer {marker} at ({x}, {y}) database units");
+        for job in &mut jobs {
+            job.pattern += shift;
+        }
+    }
+    if let Some(out) = bbox_out {
+        let bbox = combined_bbox(layer_patterns(&jobs));
+        jobs.push(Job {
+            layer: None,
+            pattern: bbox.map(Region::rect).into_iter().collect(),
+            out,
+            gerber: GerberOptions {
+                outline: true,
+                ..gerber_opts.clone()
+            },
+        });
+    }
+    if let Some(max) = args.max_file_bytes {
+        if format != OutputFormat::Gerber {
+            bail!("--max-file-bytes only applies to Gerber output");
+        }
+        if args.invert {
+            bail!("--max-file-bytes cannot split an inverted layer");
+        }
+        let mut split = vec![];
+        for job in jobs {
+            let parts = job.pattern.split_to_fit(&lib, &job.gerber, max)?;
+            if parts.len() == 1 {
+                split.push(job);
+                continue;
+            }
+            info!("Splitting {} into {} parts", job.out.display(), parts.len());
+            for (k, pattern) in parts.into_iter().enumerate() {
+                split.push(Job {
+                    layer: job.layer,
+                    pattern,
+                    out: part_path(&job.out, k + 1),
+                    gerber: job.gerber.clone(),
+                });
+            }
+        }
+        jobs = split;
+    }
+    let reports = [
+        &args.svg_all,
+        &args.aperture_report,
+        &args.csv,
+        &args.summary,
+    ];
+    // Only now is every name final, split parts included, so nothing has been written yet
+    // if two of them turn out to be the same file
+    let all_outputs = jobs
+        .iter()
+        .map(|j| &j.out)
+        .chain(reports.into_iter().flatten())
+        .chain(&args.manifest);
+    if let Some(clash) = all_outputs.duplicates().next() {
+        bail!(
+            "Multiple outputs would be written to {}, refusing to overwrite",
+            clash.display()
+        );
+    }
+    let report = if args.report_user_units {
+        ReportUnit {
+            per_mm: user_units_per_mm(&lib),
+            name: "user units",
+        }
+    } else {
+        ReportUnit {
+            per_mm: 1.,
+            name: "mm",
+        }
+    };
+    write_all(&jobs, args.jobs.unwrap_or(1) as usize, |job| {
+        let mut w = BufWriter::new(File::create(&job.out)?);
+        match format {
+            OutputFormat::Gerber => job.pattern.write_gerber(&mut w, &lib, &job.gerber)?,
+            OutputFormat::Geojson => {
+                let holes = job.gerber.nested_polarity;
+                job.pattern.write_geojson(&mut w, &lib, job.layer, holes)?
+            }
+        }
+        w.flush()?;
+        let extents = match job.pattern.bbox() {
+            Some((lo, hi)) => {
+                let ((x0, y0), (x1, y1)) = (lo.as_mm(&lib), hi.as_mm(&lib));
+                let [x0, y0, x1, y1] = [x0, y0, x1, y1].map(|v| report.show(v));
+                format!(" spanning ({x0}, {y0}) to ({x1}, {y1}) {}", report.name)
+            }
+            None => String::new(),
+        };
+        info!(
+            "Wrote {} regions{extents} to {}",
+            job.pattern.len(),
+            job.out.display()
+        );
+        Ok(())
+    })?;
+    if let Some(path) = &args.svg_all {
+        let layers = jobs
+            .iter()
+            .filter_map(|j| {
+                let layer = j.layer?;
+                let style = args.layer_styles.get(&layer);
+                let id = match style.and_then(|s| s.label.clone()) {
+                    Some(label) => label,
+                    None => format!("layer-{layer}"),
+                };
+                let color = style.and_then(|s| s.color.as_deref());
+                Some((id, color, &j.pattern))
+            })
+            .collect_vec();
+        let mut w = BufWriter::new(File::create(path)?);
+        write_svg_stack(&mut w, &layers, &lib)?;
+        w.flush()?;
+    }
+    if let Some(path) = &args.aperture_report {
+        let mut w = BufWriter::new(File::create(path)?);
+        for job in &jobs {
+            writeln!(w, "{}", job.out.display())?;
+            for ap in job.pattern.apertures(&lib, &job.gerber) {
+                let shape = match ap.shape {
+                    ApertureShape::Circle { diameter } => {
+                        format!("circle {} {}", report.show(diameter), report.name)
+                    }
+                    ApertureShape::Rectangle { width, height } => format!(
+                        "rectangle {} x {} {}",
+                        report.show(width),
+                        report.show(height),
+                        report.name
+                    ),
+                };
+                writeln!(w, "  D{} {shape}, {} uses", ap.code, ap.uses)?;
+            }
+        }
+        w.flush()?;
+    }
+    if let Some(path) = &args.csv {
+        // The columns are always millimeters, whatever unit the other reports use
+        let mm = ReportUnit {
+            per_mm: 1.,
+            name: "mm",
+        };
+        let db_mm = lib.units.db_unit() * 1e3;
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(
+            w,
+            "layer,datatype,centroid_x_mm,centroid_y_mm,area_mm2,vertices"
+        )?;
+        for job in &jobs {
+            let Some(layer) = job.layer else { continue };
+            for region in job.pattern.regions() {
+                let (x, y) = region.centroid();
+                let vertices = match region.points() {
+                    [first, .., last] if first == last => region.points().len() - 1,
+                    points => points.len(),
+                };
+                writeln!(
+                    w,
+                    "{layer},{},{},{},{},{vertices}",
+                    region.datatype(),
+                    mm.show(x * db_mm),
+                    mm.show(y * db_mm),
+                    mm.show_area(region.signed_area().abs() * db_mm * db_mm)
+                )?;
+            }
+        }
+        w.flush()?;
+    }
+    if let Some(path) = &args.summary {
+        let outputs = jobs.iter().map(|j| (j.layer, j.out.as_path(), &j.pattern));
+        summary::write(path, &lib, outputs)?;
+    }
+    // Last, so the side reports above are covered along with the layer files
+    if let Some(path) = &args.manifest {
+        let written = jobs
+            .iter()
+            .map(|j| &j.out)
+            .chain(reports.into_iter().flatten())
+            .collect_vec();
+        manifest::write(path, &written)?;
+    }
+    if opts.allow_missing_refs {
+        let missing = missing_refs(&lib, &cell)?
+            .into_iter()
+            .filter(|name| !opts.exclude_cells.contains(name))
+            .collect_vec();
+        if !missing.is_empty() {
+            warn!(
+                "Skipped references to cells missing from the library: {}",
+                missing.join(", ")
+            );
+        }
     }
     Ok(())
 }
 
-#[derive(Debug)]
-struct Pattern(Vec<Region>);
+/// One layer's line of the --diff report, with the extent of the differences in millimeters
+fn diff_line(layer: i16, diff: &PatternDiff, lib: &gds21::GdsLibrary) -> String {
+    let extent = match diff.bbox {
+        Some((lo, hi)) => {
+            let ((x0, y0), (x1, y1)) = (lo.as_mm(lib), hi.as_mm(lib));
+            format!(" within ({x0}, {y0}) to ({x1}, {y1}) mm")
+        }
+        None => String::new(),
+    };
+    format!(
+        "layer {layer}: {} added, {} removed, {} changed{extent}",
+        diff.added, diff.removed, diff.changed
+    )
+}
 
-impl Pattern {
-    fn from_gds_struct(lib: &GdsLibrary, name: &str, layer: i16) -> PatternResult<Self> {
-        let struc = lib
-            .structs
-            .iter()
-            .find(|s| s.name == name)
-            .ok_or(PatternError::PatternDoesNotExist)?;
-        let mut regions: Vec<Region> = vec![];
-        for elem in &struc.elems {
-            match elem {
-                GdsElement::GdsBoundary(b) if b.layer == layer => {
-                    regions.push(b.xy.iter().collect())
-                }
-                GdsElement::GdsBoundary(_) => {}
-                GdsElement::GdsStructRef(GdsStructRef { name, xy, .. }) => {
-                    let pat = Pattern::from_gds_struct(lib, name, layer)? + xy.into();
-                    regions.extend(pat.0);
-                }
-                GdsElement::GdsTextElem(_) => {}
-                _ => unimplemented!("{elem:?}"),
-            }
-        }
-        Ok(Self(regions))
-    }
-    fn write_gerber(&self, w: &mut impl Write, lib: &GdsLibrary) -> GerberResult<()> {
-        use gerber_types::*;
-        let co_fmt = CoordinateFormat::new(6, 6);
-        ExtendedCode::CoordinateFormat(co_fmt).serialize(w)?;
-        ExtendedCode::Unit(gerber_types::Unit::Millimeters).serialize(w)?;
-        GCode::RegionMode(true).serialize(w)?;
-        for region in &self.0 {
-            DCode::Operation(Operation::Move(Coordinates {
-                x: Some(coord_from_gds(region.0[0].x, lib)),
-                y: Some(coord_from_gds(region.0[0].y, lib)),
-                format: co_fmt,
-            }))
-            .serialize(w)?;
-            for point in &region.0 {
-                DCode::Operation(gerber_types::Operation::Interpolate(
-                    Coordinates {
-                        x: Some(coord_from_gds(point.x, lib)),
-                        y: Some(coord_from_gds(point.y, lib)),
-                        format: co_fmt,
-                    },
-                    None,
-                ))
-                .serialize(w)?;
-            }
-        }
-        GCode::RegionMode(false).serialize(w)?;
-        MCode::EndOfFile.serialize(w)?;
-        Ok(())
+/// Patterns of the requested layers, leaving out derived outputs such as solder masks whose
+/// expansion would otherwise grow the extents
+fn layer_patterns(jobs: &[Job]) -> impl Iterator<Item = &Pattern> {
+    jobs.iter()
+        .filter(|j| j.layer.is_some())
+        .map(|j| &j.pattern)
+}
+
+/// Runs `write` over every job on at most `threads` threads, reporting the first failure
+/// in job order
+fn write_all<T: Sync>(
+    jobs: &[T],
+    threads: usize,
+    write: impl Fn(&T) -> Result<()> + Sync,
+) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|s| {
+        let workers = (0..threads.clamp(1, jobs.len().max(1)))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = jobs.get(i) else {
+                            break done;
+                        };
+                        done.push((i, write(job)));
+                    }
+                })
+            })
+            .collect_vec();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("writer thread panicked"))
+            .collect_vec()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().try_for_each(|(_, r)| r)
+}
+
+/// Unit that lengths in reports are shown in; the output geometry is always millimeters
+struct ReportUnit {
+    per_mm: f64,
+    name: &'static str,
+}
+
+impl ReportUnit {
+    /// Converts a length in millimeters, rounded to nine decimals to drop float noise, and
+    /// with small negatives that round away shown as 0 rather than -0
+    fn show(&self, mm: f64) -> f64 {
+        (mm * self.per_mm * 1e9).round() / 1e9 + 0.
     }
+    /// Converts an area in square millimeters, rounded to the square of [`Self::show`]'s step
+    /// or to 15 significant digits if that is coarser, since areas span a far wider range
+    fn show_area(&self, mm2: f64) -> f64 {
+        let area = mm2 * self.per_mm * self.per_mm;
+        let decimals = (14. - area.abs().log10().floor()).min(18.) as i32;
+        let scale = 10f64.powi(decimals);
+        (area * scale).round() / scale + 0.
+    }
+}
+
+/// Grid of copies that the converted cell is repeated on
+#[derive(Debug, Clone)]
+struct Panel {
+    cols: u32,
+    rows: u32,
+    /// Spacing between columns, in millimeters
+    dx: f64,
+    /// Spacing between rows, in millimeters
+    dy: f64,
+}
+
+fn parse_crop(s: &str) -> Result<[f64; 4], String> {
+    let err = || format!("expected X0,Y0,X1,Y1 like 0,0,25.4,10, got `{s}`");
+    let [x0, y0, x1, y1] = s.split(',').map(str::trim).collect_vec()[..] else {
+        return Err(err());
+    };
+    let mm = |v: &str| {
+        v.parse::<f64>()
+            .ok()
+            .filter(|d| d.is_finite())
+            .ok_or_else(err)
+    };
+    Ok([mm(x0)?, mm(y0)?, mm(x1)?, mm(y1)?])
+}
+
+fn parse_panel(s: &str) -> Result<Panel, String> {
+    let err = || format!("expected COLS,ROWS,DX,DY like 2,3,10.5,8, got `{s}`");
+    let [cols, rows, dx, dy] = s.split(',').map(str::trim).collect_vec()[..] else {
+        return Err(err());
+    };
+    let count = |v: &str| v.parse::<u32>().ok().filter(|&n| n > 0).ok_or_else(err);
+    let mm = |v: &str| {
+        v.parse::<f64>()
+            .ok()
+            .filter(|d| d.is_finite())
+            .ok_or_else(err)
+    };
+    Ok(Panel {
+        cols: count(cols)?,
+        rows: count(rows)?,
+        dx: mm(dx)?,
+        dy: mm(dy)?,
+    })
 }
 
-fn coord_from_gds(v: i32, lib: &GdsLibrary) -> CoordinateNumber {
-    let unit = lib.units.db_unit();
-    let meters = v as f64 * unit;
-    let millis = meters * 1000.;
-    <CoordinateNumber as conv::TryFrom<f64>>::try_from(millis).unwrap()
+/// Kind of file written for each layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Gerber,
+    /// A FeatureCollection of polygons in millimeters
+    Geojson,
 }
 
-impl std::ops::Add<Point> for Pattern {
-    type Output = Pattern;
+impl FromStr for OutputFormat {
+    type Err = String;
 
-    fn add(mut self, rhs: Point) -> Self::Output {
-        for r in &mut self.0 {
-            *r += rhs;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gerber" => Ok(Self::Gerber),
+            "geojson" => Ok(Self::Geojson),
+            _ => Err(format!(
+                "unknown output format `{s}`, expected gerber or geojson"
+            )),
         }
-        self
     }
 }
 
-#[derive(Debug)]
-struct Region(Vec<Point>);
-impl<I> FromIterator<I> for Region
-where
-    I: Into<Point>,
-{
-    fn from_iter<T: IntoIterator<Item = I>>(iter: T) -> Self {
-        Self(iter.into_iter().map(|v| v.into()).collect())
-    }
+/// How a failure is reported on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+    Human,
+    /// One JSON object with the error's kind, message and fields
+    Json,
 }
-impl std::ops::AddAssign<Point> for Region {
-    fn add_assign(&mut self, rhs: Point) {
-        for p in &mut self.0 {
-            *p = *p + rhs;
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown error format `{s}`, expected human or json"
+            )),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Point {
-    x: i32,
-    y: i32,
+/// Where the output origin sits relative to the converted cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LocalOrigin {
+    /// The cell's own origin, leaving coordinates as authored
+    Authored,
+    /// The center of the bounding box of everything converted
+    Center,
 }
-impl std::ops::Add for Point {
-    type Output = Point;
 
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            x: self.x + rhs.x,
-            y: self.y + rhs.y,
+impl FromStr for LocalOrigin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "authored" => Ok(Self::Authored),
+            "center" => Ok(Self::Center),
+            _ => Err(format!("unknown origin `{s}`, expected authored or center")),
         }
     }
 }
-impl From<&GdsPoint> for Point {
-    fn from(p: &GdsPoint) -> Self {
-        Self { x: p.x, y: p.y }
-    }
+
+fn output_path(template: &str, file: &str, cell: &str, layer: &str) -> PathBuf {
+    template
+        .replace("{file}", file)
+        .replace("{cell}", cell)
+        .replace("{layer}", layer)
+        .into()
 }
 
-type PatternResult<T> = Result<T, PatternError>;
+/// `out` with `_part<k>` inserted before its extension
+fn part_path(out: &Path, k: usize) -> PathBuf {
+    let stem = out.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match out.extension() {
+        Some(ext) => format!("{stem}_part{k}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_part{k}"),
+    };
+    out.with_file_name(name)
+}
+
+/// One Gerber file to produce
+struct Job {
+    /// GDS layer the pattern was taken from, `None` for derived outputs
+    layer: Option<i16>,
+    pattern: Pattern,
+    out: PathBuf,
+    gerber: GerberOptions,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Mutex};
+
+    use gds21::{GdsBoundary, GdsElement, GdsLibrary, GdsPoint, GdsStruct, GdsStructRef};
+    use gdsii_to_gerber::capture_stderr;
+
+    use super::*;
+
+    /// `run` sets the process-wide verbosity, so the tests driving it take turns
+    static RUNNING: Mutex<()> = Mutex::new(());
 
-#[derive(Error, Debug)]
-enum PatternError {
-    #[error("The requested pattern name does not exist in the library")]
-    PatternDoesNotExist,
+    /// Empty directory of the test's own
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gdsii_to_gerber-{}-{test}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `name` inside `dir`, as a command line argument
+    fn at(dir: &Path, name: &str) -> String {
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    fn rect(layer: i16, lo: (i32, i32), hi: (i32, i32)) -> GdsElement {
+        let corners = [lo, (hi.0, lo.1), hi, (lo.0, hi.1), lo];
+        GdsBoundary {
+            layer,
+            xy: GdsPoint::vec(&corners),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    /// Saves the cells as `lib.gds` in `dir`, with 1 nm database units
+    fn save(dir: &Path, cells: Vec<(&str, Vec<GdsElement>)>) -> String {
+        let mut lib = GdsLibrary::new("test");
+        for (name, elems) in cells {
+            let mut struc = GdsStruct::new(name);
+            struc.elems = elems;
+            lib.structs.push(struc);
+        }
+        let path = at(dir, "lib.gds");
+        lib.save(&path).unwrap();
+        path
+    }
+
+    /// Names of the files in `dir` besides the input library, sorted
+    fn outputs(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "lib.gds")
+            .sorted()
+            .collect()
+    }
+
+    /// Parses and runs a command line given without the program name, returning the
+    /// outcome along with the warnings and progress lines it printed
+    fn gds2ger(args: &[&str]) -> (Result<()>, Vec<String>) {
+        let _turn = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        capture_stderr(|| run(Args::try_parse_from(["gds2ger"].iter().chain(args))?))
+    }
+
+    #[test]
+    fn colliding_layer_outputs_fail_before_writing() {
+        let dir = scratch("collide-layers");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![rect(1, (0, 0), (10, 10)), rect(2, (0, 0), (5, 5))],
+            )],
+        );
+        let out = at(&dir, "out.g");
+        let err = gds2ger(&[&gds, "--cell", "TOP", "1", "2", "--output", &out])
+            .0
+            .unwrap_err();
+        assert!(err.to_string().contains("refusing to overwrite"), "{err}");
+        assert!(outputs(&dir).is_empty());
+    }
+
+    #[test]
+    fn reports_colliding_with_a_layer_fail_before_writing() {
+        let dir = scratch("collide-report");
+        let gds = save(&dir, vec![("TOP", vec![rect(1, (0, 0), (10, 10))])]);
+        let out = at(&dir, "{layer}.g");
+        let csv = at(&dir, "1.g");
+        let err = gds2ger(&[&gds, "--cell", "TOP", "1", "--output", &out, "--csv", &csv])
+            .0
+            .unwrap_err();
+        assert!(err.to_string().contains("refusing to overwrite"), "{err}");
+        assert!(outputs(&dir).is_empty());
+    }
+
+    #[test]
+    fn split_parts_colliding_with_a_layer_fail_before_writing() {
+        let dir = scratch("collide-parts");
+        let squares = (0..20).map(|i| rect(1, (i * 100, 0), (i * 100 + 50, 50)));
+        let gds = save(
+            &dir,
+            vec![("TOP", squares.chain([rect(2, (0, 0), (5, 5))]).collect())],
+        );
+        let config = at(&dir, "c.toml");
+        fs::write(&config, "[layers]\n1 = \"a\"\n2 = \"a_part1\"\n").unwrap();
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "--config", &config, "--output", &out];
+        let err = gds2ger(&[&args[..], &["--max-file-bytes", "400"]].concat())
+            .0
+            .unwrap_err();
+        assert!(err.to_string().contains("a_part1.g"), "{err}");
+        assert_eq!(outputs(&dir), ["c.toml"]);
+    }
+
+    #[test]
+    fn quiet_silences_warnings_but_not_errors() {
+        let dir = scratch("quiet");
+        let gds = save(&dir, vec![("TOP", vec![rect(1, (0, 0), (10, 10))])]);
+        let out = at(&dir, "{layer}.g");
+        let (result, lines) = gds2ger(&[&gds, "--cell", "TOP", "5", "--output", &out]);
+        result.unwrap();
+        assert_eq!(
+            lines,
+            ["warning: Layer 5 is not present anywhere in the library"]
+        );
+
+        let (result, lines) = gds2ger(&[&gds, "--cell", "TOP", "5", "--output", &out, "--quiet"]);
+        result.unwrap();
+        assert!(lines.is_empty(), "{lines:?}");
+
+        let (result, _) = gds2ger(&[&gds, "--cell", "NOPE", "1", "--output", &out, "--quiet"]);
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("NOPE does not exist"), "{err}");
+    }
+
+    #[test]
+    fn quiet_and_verbose_exclude_each_other() {
+        assert!(Args::try_parse_from(["gds2ger", "f.gds", "--cell", "TOP", "-q", "-v"]).is_err());
+    }
+
+    /// Command line parsed and completed from a config given as TOML text
+    fn configured(args: &[&str], toml: &str) -> Result<Args> {
+        let mut args = Args::try_parse_from(["gds2ger"].iter().chain(args))?;
+        args.apply_config(toml::from_str(toml)?)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn config_sets_every_option() {
+        let args = configured(
+            &["f.gds"],
+            r#"
+            cell = "TOP"
+            layers = [3, 4]
+            datatype = [1]
+            datatype-range = ["2-3"]
+            strict = true
+            expect-layers = true
+            center = true
+            crop = "0,0,2,1"
+            panel = "2,3,10,5"
+            optimize-travel = true
+            keep-largest = 7
+            facets = 16
+            format = "geojson"
+            layer-offset = 10
+            output = "out_{layer}.g"
+            coord-format = "4.5"
+            placeholder-depth = 2
+            bias = 0.01
+            bias-join = "round"
+            mask-expand = 0.05
+            exclude-cell = ["FILL"]
+            add-bbox-outline = true
+            holes = true
+            flash-rects = true
+            invert = true
+            image-polarity = "neg"
+            board-outline = true
+            allow-missing-refs = true
+            outline-width = 0.2
+            gerber-style = "compact"
+            svg-all = "all.svg"
+            max-file-bytes = 1000
+            jobs = 4
+            aperture-report = "ap.txt"
+            report-user-units = true
+            csv = "r.csv"
+            manifest = "m.json"
+            summary = "s.json"
+            stream = true
+            verbose = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(args.cell.as_deref(), Some("TOP"));
+        assert_eq!(args.layers, [3, 4]);
+        assert_eq!(args.datatype, [1]);
+        assert_eq!(args.datatype_range, [2..=3]);
+        assert!(args.strict && args.expect_layers && args.center);
+        assert_eq!(args.crop, Some([0., 0., 2., 1.]));
+        let panel = args.panel.unwrap();
+        assert_eq!(
+            (panel.cols, panel.rows, panel.dx, panel.dy),
+            (2, 3, 10., 5.)
+        );
+        assert!(args.optimize_travel);
+        assert_eq!(args.keep_largest, Some(7));
+        assert_eq!(args.facets, Some(16));
+        assert_eq!(args.format, Some(OutputFormat::Geojson));
+        assert_eq!(args.layer_offset, Some(10));
+        assert_eq!(args.output.as_deref(), Some("out_{layer}.g"));
+        assert_eq!(args.coord_format, Some(CoordinateFormat::new(4, 5)));
+        assert_eq!(args.placeholder_depth, Some(2));
+        assert_eq!(args.bias, Some(0.01));
+        assert_eq!(args.bias_join, Some(Join::Round));
+        assert_eq!(args.mask_expand, Some(0.05));
+        assert_eq!(args.exclude_cell, ["FILL"]);
+        assert!(args.add_bbox_outline && args.holes && args.flash_rects && args.invert);
+        assert_eq!(args.image_polarity, Some(ImagePolarity::Negative));
+        assert!(args.board_outline && args.allow_missing_refs);
+        assert_eq!(args.outline_width, Some(0.2));
+        assert_eq!(args.gerber_style, Some(GerberStyle::Compact));
+        assert_eq!(args.svg_all, Some("all.svg".into()));
+        assert_eq!(args.max_file_bytes, Some(1000));
+        assert_eq!(args.jobs, Some(4));
+        assert_eq!(args.aperture_report, Some("ap.txt".into()));
+        assert!(args.report_user_units);
+        assert_eq!(args.csv, Some("r.csv".into()));
+        assert_eq!(args.manifest, Some("m.json".into()));
+        assert_eq!(args.summary, Some("s.json".into()));
+        assert!(args.stream && args.verbose && !args.quiet);
+    }
+
+    #[test]
+    fn config_sets_the_alternative_origin_and_crop_options() {
+        let toml = r#"
+            local-origin = "center"
+            crop-from-layer = 9
+            quiet = true
+        "#;
+        let args = configured(&["f.gds"], toml).unwrap();
+        assert_eq!(args.local_origin, Some(LocalOrigin::Center));
+        assert_eq!(args.crop_from_layer, Some(9));
+        assert!(args.quiet);
+        let toml = r#"
+            origin-from-layer = 8
+            origin-first = true
+        "#;
+        let args = configured(&["f.gds"], toml).unwrap();
+        assert_eq!(args.origin_from_layer, Some(8));
+        assert!(args.origin_first);
+    }
+
+    #[test]
+    fn command_line_overrides_the_config() {
+        let toml = r#"
+            cell = "TOP"
+            layers = [3, 4]
+            facets = 16
+            center = true
+            crop = "0,0,2,1"
+            output = "config_{layer}.g"
+            verbose = true
+        "#;
+        let args = configured(
+            &[
+                "f.gds",
+                "5",
+                "--cell",
+                "OTHER",
+                "--facets",
+                "8",
+                "--origin-from-layer",
+                "7",
+                "--crop-from-layer",
+                "6",
+                "--quiet",
+            ],
+            toml,
+        )
+        .unwrap();
+        assert_eq!(args.cell.as_deref(), Some("OTHER"));
+        assert_eq!(args.layers, [5]);
+        assert_eq!(args.facets, Some(8));
+        assert_eq!(args.origin_from_layer, Some(7));
+        assert!(!args.center && args.crop.is_none());
+        assert_eq!(args.crop_from_layer, Some(6));
+        assert_eq!(args.output.as_deref(), Some("config_{layer}.g"));
+        assert!(args.quiet && !args.verbose);
+    }
+
+    #[test]
+    fn config_rejects_unknown_keys() {
+        let err = configured(&["f.gds"], "facet = 8").unwrap_err();
+        assert!(err.to_string().contains("unknown field `facet`"), "{err}");
+    }
+
+    #[test]
+    fn manifest_lists_every_output_with_its_size() {
+        let dir = scratch("manifest");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![rect(1, (0, 0), (10, 10)), rect(2, (0, 0), (5, 5))],
+            )],
+        );
+        let (out, csv, manifest) = (at(&dir, "{layer}.g"), at(&dir, "r.csv"), at(&dir, "m.txt"));
+        let args = [
+            &gds, "--cell", "TOP", "1", "2", "--output", &out, "--csv", &csv,
+        ];
+        gds2ger(&[&args[..], &["--manifest", &manifest]].concat())
+            .0
+            .unwrap();
+        let listed = fs::read_to_string(&manifest)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let [_, size, path] = line.splitn(3, ' ').collect_vec()[..] else {
+                    panic!("{line}")
+                };
+                (path.to_string(), size.parse::<u64>().unwrap())
+            })
+            .collect_vec();
+        let expected = ["1.g", "2.g", "r.csv"]
+            .map(|name| (at(&dir, name), fs::metadata(dir.join(name)).unwrap().len()));
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn parallel_writing_matches_serial() {
+        let dir = scratch("jobs");
+        let shapes = (1..=8)
+            .flat_map(|layer| {
+                (0..layer).map(move |i| rect(layer as i16, (i * 20, 0), (i * 20 + 10, 10)))
+            })
+            .collect();
+        let gds = save(&dir, vec![("TOP", shapes)]);
+        let layers = (1..=8).map(|l| l.to_string()).collect_vec();
+        let layers = layers.iter().map(String::as_str).collect_vec();
+        let written = |jobs: &str| {
+            let out = at(&dir, &format!("j{jobs}_{{layer}}.g"));
+            let args = [&gds, "--cell", "TOP", "--output", &out, "--jobs", jobs];
+            gds2ger(&[&args[..], &layers].concat()).0.unwrap();
+            (1..=8)
+                .map(|l| fs::read(dir.join(format!("j{jobs}_{l}.g"))).unwrap())
+                .collect_vec()
+        };
+        assert_eq!(written("4"), written("1"));
+    }
+
+    #[test]
+    fn parallel_writing_reports_the_first_failure_in_job_order() {
+        let jobs = (0..16).collect_vec();
+        let result = write_all(&jobs, 4, |&i| match i {
+            3 | 9 => bail!("job {i} failed"),
+            _ => Ok(()),
+        });
+        assert_eq!(result.unwrap_err().to_string(), "job 3 failed");
+    }
+
+    #[test]
+    fn expect_layers_rejects_unrequested_geometry() {
+        let dir = scratch("expect-layers");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![rect(1, (0, 0), (10, 10)), rect(2, (0, 0), (5, 5))],
+            )],
+        );
+        let out = at(&dir, "{layer}.g");
+        let err = gds2ger(&[
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "--output",
+            &out,
+            "--expect-layers",
+        ])
+        .0
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The cell has geometry on layers that were not requested: 2"
+        );
+        assert!(outputs(&dir).is_empty());
+        gds2ger(&[
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "2",
+            "--output",
+            &out,
+            "--expect-layers",
+        ])
+        .0
+        .unwrap();
+    }
+
+    #[test]
+    fn expect_layers_ignores_what_conversion_leaves_out() {
+        let dir = scratch("expect-layers-filters");
+        let other_datatype = GdsBoundary {
+            layer: 4,
+            datatype: 9,
+            xy: GdsPoint::vec(&[(0, 0), (1, 0), (0, 1), (0, 0)]),
+            ..Default::default()
+        };
+        let gds = save(
+            &dir,
+            vec![
+                ("FILL", vec![rect(2, (0, 0), (5, 5))]),
+                (
+                    "TOP",
+                    vec![
+                        rect(1, (0, 0), (10, 10)),
+                        rect(3, (4, 4), (6, 6)),
+                        other_datatype.into(),
+                        GdsStructRef {
+                            name: "FILL".into(),
+                            xy: GdsPoint::new(0, 0),
+                            ..Default::default()
+                        }
+                        .into(),
+                    ],
+                ),
+            ],
+        );
+        let out = at(&dir, "{layer}.g");
+        let args = [
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "--output",
+            &out,
+            "--expect-layers",
+        ];
+        let filters = [
+            "--exclude-cell",
+            "FILL",
+            "--datatype",
+            "0",
+            "--origin-from-layer",
+            "3",
+        ];
+        gds2ger(&[&args[..], &filters].concat()).0.unwrap();
+        assert_eq!(outputs(&dir), ["1.g"]);
+    }
+
+    #[test]
+    fn bbox_outline_is_a_single_rectangle_around_every_layer() {
+        let dir = scratch("bbox-outline");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![rect(1, (0, 0), (10, 10)), rect(2, (20, 5), (30, 40))],
+            )],
+        );
+        let out = at(&dir, "{layer}.g");
+        let args = [
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "2",
+            "--output",
+            &out,
+            "--add-bbox-outline",
+        ];
+        gds2ger(&[&args[..], &["--center"]].concat()).0.unwrap();
+        assert_eq!(outputs(&dir), ["1.g", "2.g", "bbox.g"]);
+        let bbox = fs::read_to_string(dir.join("bbox.g")).unwrap();
+        let moves = bbox.lines().filter(|l| l.ends_with("D02*")).collect_vec();
+        let draws = bbox.lines().filter(|l| l.ends_with("D01*")).collect_vec();
+        assert_eq!(moves, ["X-15Y-20D02*"]);
+        assert_eq!(
+            draws,
+            [
+                "X-15Y-20D01*",
+                "X15Y-20D01*",
+                "X15Y20D01*",
+                "X-15Y20D01*",
+                "X-15Y-20D01*",
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_fails_on_degenerate_regions_that_are_otherwise_dropped() {
+        let dir = scratch("degenerate");
+        let collinear = GdsBoundary {
+            layer: 1,
+            xy: GdsPoint::vec(&[(0, 0), (10, 10), (20, 20), (0, 0)]),
+            ..Default::default()
+        };
+        let gds = save(
+            &dir,
+            vec![("TOP", vec![collinear.into(), rect(1, (0, 0), (10, 10))])],
+        );
+        let out = at(&dir, "{layer}.g");
+        let (res, stderr) = gds2ger(&[&gds, "--cell", "TOP", "1", "--output", &out, "--strict"]);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Layer 1 has 1 zero-area regions"
+        );
+        assert!(stderr.is_empty());
+        assert!(outputs(&dir).is_empty());
+        let (res, stderr) = gds2ger(&[&gds, "--cell", "TOP", "1", "--output", &out]);
+        res.unwrap();
+        assert_eq!(stderr, ["warning: Dropped 1 zero-area regions on layer 1"]);
+        assert_eq!(outputs(&dir), ["1.g"]);
+    }
+
+    #[test]
+    fn missing_layers_say_whether_the_library_has_them() {
+        let dir = scratch("missing-layers");
+        let gds = save(
+            &dir,
+            vec![
+                ("OTHER", vec![rect(5, (0, 0), (10, 10))]),
+                ("TOP", vec![rect(1, (0, 0), (10, 10))]),
+            ],
+        );
+        let out = at(&dir, "{layer}.g");
+        let (res, stderr) = gds2ger(&[&gds, "--cell", "TOP", "1", "5", "7", "--output", &out]);
+        res.unwrap();
+        assert_eq!(
+            stderr,
+            [
+                "warning: Layer 5 is present elsewhere in the library but not in cell TOP",
+                "warning: Layer 7 is not present anywhere in the library",
+            ]
+        );
+    }
+
+    #[test]
+    fn dangling_references_are_skipped_only_when_allowed() {
+        let dir = scratch("dangling");
+        let dangling = |name: &str| GdsStructRef {
+            name: name.into(),
+            xy: GdsPoint::new(0, 0),
+            ..Default::default()
+        };
+        let top = vec![
+            rect(1, (0, 0), (10, 10)),
+            dangling("GONE").into(),
+            dangling("LOST").into(),
+        ];
+        let gds = save(&dir, vec![("TOP", top)]);
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "1", "--output", &out];
+        let err = gds2ger(&args).0.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The cell GONE does not exist in the library"
+        );
+        assert!(outputs(&dir).is_empty());
+        let (res, stderr) = gds2ger(&[&args[..], &["--allow-missing-refs"]].concat());
+        res.unwrap();
+        assert_eq!(
+            stderr,
+            ["warning: Skipped references to cells missing from the library: GONE, LOST"]
+        );
+        assert_eq!(outputs(&dir), ["1.g"]);
+    }
+
+    #[test]
+    fn aperture_report_lists_each_pad_size_with_its_uses() {
+        let dir = scratch("aperture-report");
+        let pads = vec![
+            rect(1, (0, 0), (100_000, 50_000)),
+            rect(1, (200_000, 0), (300_000, 50_000)),
+            rect(1, (0, 100_000), (20_000, 120_000)),
+        ];
+        let gds = save(&dir, vec![("TOP", pads)]);
+        let out = at(&dir, "{layer}.g");
+        let report = at(&dir, "apertures.txt");
+        let args = [
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "--output",
+            &out,
+            "--flash-rects",
+        ];
+        gds2ger(&[&args[..], &["--aperture-report", &report]].concat())
+            .0
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(report).unwrap(),
+            format!(
+                "{out}\n  D11 rectangle 0.1 x 0.05 mm, 2 uses\n  D12 rectangle 0.02 x 0.02 mm, 1 uses\n",
+                out = at(&dir, "1.g")
+            )
+        );
+    }
+
+    #[test]
+    fn center_uses_the_rotated_extents() {
+        let dir = scratch("center-rotated");
+        let placed = GdsStructRef {
+            name: "LEAF".into(),
+            xy: GdsPoint::new(100, 100),
+            strans: Some(gds21::GdsStrans {
+                angle: Some(90.),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let gds = save(
+            &dir,
+            vec![
+                ("LEAF", vec![rect(1, (0, 0), (30, 10))]),
+                ("TOP", vec![placed.into()]),
+            ],
+        );
+        let out = at(&dir, "{layer}.g");
+        gds2ger(&[&gds, "--cell", "TOP", "1", "--output", &out, "--center"])
+            .0
+            .unwrap();
+        let gerber = fs::read_to_string(dir.join("1.g")).unwrap();
+        let mut corners = gerber
+            .lines()
+            .filter_map(|l| l.strip_suffix("D01*"))
+            .collect_vec();
+        corners.sort();
+        corners.dedup();
+        assert_eq!(corners, ["X-5Y-15", "X-5Y15", "X5Y-15", "X5Y15"]);
+    }
+
+    #[test]
+    fn positional_layers_follow_a_cell_from_the_config() {
+        let args = configured(&["f.gds", "1"], r#"cell = "TOP""#).unwrap();
+        assert_eq!(args.cell.as_deref(), Some("TOP"));
+        assert_eq!(args.layers, [1]);
+
+        let dir = scratch("config-cell");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![rect(1, (0, 0), (10, 10)), rect(2, (0, 0), (5, 5))],
+            )],
+        );
+        let config = at(&dir, "c.toml");
+        let out = at(&dir, "{layer}.g");
+        fs::write(
+            &config,
+            format!("cell = \"TOP\"\nlayers = [1, 2]\noutput = {out:?}\n"),
+        )
+        .unwrap();
+        gds2ger(&["--config", &config, &gds]).0.unwrap();
+        assert_eq!(outputs(&dir), ["1.g", "2.g", "c.toml"]);
+        fs::remove_file(dir.join("1.g")).unwrap();
+        fs::remove_file(dir.join("2.g")).unwrap();
+        gds2ger(&["--config", &config, &gds, "2"]).0.unwrap();
+        assert_eq!(outputs(&dir), ["2.g", "c.toml"]);
+    }
+
+    #[test]
+    fn panel_repeats_the_cell_at_millimeter_offsets() {
+        let dir = scratch("panel");
+        let gds = save(&dir, vec![("TOP", vec![rect(1, (0, 0), (10, 10))])]);
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "1", "--output", &out];
+        gds2ger(&[&args[..], &["--panel", "2,2,1,2"]].concat())
+            .0
+            .unwrap();
+        let gerber = fs::read_to_string(dir.join("1.g")).unwrap();
+        let moves = gerber
+            .lines()
+            .filter(|l| l.ends_with("D02*"))
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            moves,
+            [
+                "X0Y0D02*",
+                "X0Y2000000D02*",
+                "X1000000Y0D02*",
+                "X1000000Y2000000D02*",
+            ]
+        );
+        assert_eq!(gerber.matches("G36*").count(), 1);
+    }
+
+    #[test]
+    fn user_unit_reports_scale_the_millimeter_values() {
+        // The default library units put a thousand user units of a micrometer in each mm
+        let dir = scratch("user-units");
+        let gds = save(&dir, vec![("TOP", vec![rect(1, (0, 0), (100_000, 2_500))])]);
+        let out = at(&dir, "{layer}.g");
+        let report = at(&dir, "apertures.txt");
+        let args = [
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "--output",
+            &out,
+            "--flash-rects",
+        ];
+        let args = [&args[..], &["--aperture-report", &report]].concat();
+        let pad = || {
+            fs::read_to_string(&report)
+                .unwrap()
+                .lines()
+                .nth(1)
+                .unwrap()
+                .to_owned()
+        };
+        gds2ger(&args).0.unwrap();
+        assert_eq!(pad(), "  D11 rectangle 0.1 x 0.0025 mm, 1 uses");
+        gds2ger(&[&args[..], &["--report-user-units"]].concat())
+            .0
+            .unwrap();
+        assert_eq!(pad(), "  D11 rectangle 100 x 2.5 user units, 1 uses");
+    }
+
+    #[test]
+    fn layer_offset_renumbers_the_file_and_its_attribute() {
+        let dir = scratch("layer-offset");
+        let gds = save(&dir, vec![("TOP", vec![rect(1, (0, 0), (10, 10))])]);
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "1", "--output", &out];
+        gds2ger(&[&args[..], &["--layer-offset", "10"]].concat())
+            .0
+            .unwrap();
+        assert_eq!(outputs(&dir), ["11.g"]);
+        let gerber = fs::read_to_string(dir.join("11.g")).unwrap();
+        assert!(
+            gerber.contains("%TF.FileFunction,Other,L11*%\n"),
+            "{gerber}"
+        );
+        assert!(gerber.contains("X10Y10D01*"));
+    }
+
+    #[test]
+    fn diff_line_reports_counts_and_a_clean_extent() {
+        let lib = GdsLibrary::new("lib");
+        let diff = PatternDiff {
+            added: 2,
+            removed: 1,
+            changed: 0,
+            bbox: Some((
+                Point { x: -3, y: 7 },
+                Point {
+                    x: 123,
+                    y: 1_000_015,
+                },
+            )),
+        };
+        assert_eq!(
+            diff_line(4, &diff, &lib),
+            "layer 4: 2 added, 1 removed, 0 changed within (-0.000003, 0.000007) to (0.000123, 1.000015) mm"
+        );
+        assert_eq!(
+            diff_line(4, &PatternDiff::default(), &lib),
+            "layer 4: 0 added, 0 removed, 0 changed"
+        );
+    }
+
+    #[test]
+    fn origin_marker_centroid_becomes_the_origin() {
+        let dir = scratch("origin-marker");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![
+                    rect(1, (1000, 2000), (1010, 2010)),
+                    rect(9, (990, 1990), (1010, 2010)),
+                ],
+            )],
+        );
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "1", "--output", &out];
+        gds2ger(&[&args[..], &["--origin-from-layer", "9"]].concat())
+            .0
+            .unwrap();
+        let gerber = fs::read_to_string(dir.join("1.g")).unwrap();
+        let corners = gerber
+            .lines()
+            .filter_map(|l| l.strip_suffix("D01*"))
+            .unique()
+            .sorted()
+            .collect_vec();
+        assert_eq!(corners, ["X0Y0", "X0Y10", "X10Y0", "X10Y10"]);
+    }
+
+    #[test]
+    fn several_origin_markers_need_origin_first() {
+        let dir = scratch("origin-markers");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![
+                    rect(1, (0, 0), (10, 10)),
+                    rect(9, (100, 100), (110, 110)),
+                    rect(9, (500, 500), (510, 510)),
+                ],
+            )],
+        );
+        let out = at(&dir, "{layer}.g");
+        let args = [
+            &gds,
+            "--cell",
+            "TOP",
+            "1",
+            "--output",
+            &out,
+            "--origin-from-layer",
+            "9",
+        ];
+        let err = gds2ger(&args).0.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cell TOP has 2 disconnected origin markers on layer 9; pass --origin-first to use the first"
+        );
+        gds2ger(&[&args[..], &["--origin-first"]].concat())
+            .0
+            .unwrap();
+        let gerber = fs::read_to_string(dir.join("1.g")).unwrap();
+        assert!(gerber.contains("X-105Y-105D02*"), "{gerber}");
+    }
+
+    #[test]
+    fn mask_is_the_pad_grown_by_the_expansion_with_its_own_function() {
+        let dir = scratch("mask");
+        let gds = save(
+            &dir,
+            vec![("TOP", vec![rect(1, (0, 0), (100_000, 50_000))])],
+        );
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "1", "--output", &out];
+        gds2ger(&[&args[..], &["--mask-expand", "0.01"]].concat())
+            .0
+            .unwrap();
+        assert_eq!(outputs(&dir), ["1.g", "1_mask.g"]);
+        let read = |name| fs::read_to_string(dir.join(name)).unwrap();
+        let (copper, mask) = (read("1.g"), read("1_mask.g"));
+        assert!(copper.contains("%TF.FileFunction,Other,L1*%\n"));
+        assert!(mask.contains("%TF.FileFunction,Other,L1-mask*%\n"));
+        let corners = mask
+            .lines()
+            .filter_map(|l| l.strip_suffix("D01*"))
+            .unique()
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            corners,
+            [
+                "X-10000Y-10000",
+                "X-10000Y60000",
+                "X110000Y-10000",
+                "X110000Y60000"
+            ]
+        );
+    }
+
+    #[test]
+    fn crop_layer_rectangle_clips_the_other_layers() {
+        let dir = scratch("crop-layer");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![
+                    rect(1, (0, 0), (100, 100)),
+                    rect(1, (300, 300), (310, 310)),
+                    rect(5, (50, 50), (200, 200)),
+                ],
+            )],
+        );
+        let out = at(&dir, "{layer}.g");
+        let args = [&gds, "--cell", "TOP", "1", "--output", &out];
+        gds2ger(&[&args[..], &["--crop-from-layer", "5"]].concat())
+            .0
+            .unwrap();
+        let gerber = fs::read_to_string(dir.join("1.g")).unwrap();
+        let corners = gerber
+            .lines()
+            .filter_map(|l| l.strip_suffix("D01*"))
+            .unique()
+            .sorted()
+            .collect_vec();
+        assert_eq!(corners, ["X100Y100", "X100Y50", "X50Y100", "X50Y50"]);
+        let err = gds2ger(&[&args[..], &["--crop-from-layer", "1"]].concat())
+            .0
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Layer 1 of cell TOP must hold exactly one rectangle to crop to, found 2 regions"
+        );
+    }
+
+    #[test]
+    fn report_values_never_show_negative_zero() {
+        let mm = ReportUnit {
+            per_mm: 1.,
+            name: "mm",
+        };
+        assert_eq!(mm.show(-1e-12).to_string(), "0");
+        assert_eq!(mm.show(-0.).to_string(), "0");
+        assert_eq!(mm.show(-0.25).to_string(), "-0.25");
+        assert_eq!(mm.show_area(-0.).to_string(), "0");
+    }
+
+    #[test]
+    fn csv_has_a_row_per_region_with_its_area() {
+        let dir = scratch("csv");
+        let gds = save(
+            &dir,
+            vec![(
+                "TOP",
+                vec![
+                    rect(1, (-1_000_000, -1_000_000), (1_000_000, 1_000_000)),
+                    rect(2, (0, 0), (500_000, 250_000)),
+                ],
+            )],
+        );
+        let out = at(&dir, "{layer}.g");
+        let csv = at(&dir, "regions.csv");
+        let args = [&gds, "--cell", "TOP", "1", "2", "--output", &out];
+        gds2ger(&[&args[..], &["--csv", &csv]].concat()).0.unwrap();
+        assert_eq!(
+            fs::read_to_string(csv).unwrap(),
+            "layer,datatype,centroid_x_mm,centroid_y_mm,area_mm2,vertices\n\
+             1,0,0,0,4,4\n\
+             2,0,0.25,0.125,0.125,4\n"
+        );
+    }
 }
@@ -0,0 +1,77 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Records size and SHA-256 of every produced file, as JSON for a `.json` path and as
+/// `<sha256> <size> <path>` lines otherwise
+pub fn write(manifest: &Path, files: &[impl AsRef<Path>]) -> Result<()> {
+    let entries: Vec<Entry> = files
+        .iter()
+        .map(|f| {
+            let bytes = fs::read(f)?;
+            Ok(Entry {
+                path: f.as_ref().display().to_string(),
+                size: bytes.len() as u64,
+                sha256: Sha256::digest(&bytes)
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .join(""),
+            })
+        })
+        .collect::<Result<_>>()?;
+    let text = if manifest.extension().is_some_and(|e| e == "json") {
+        serde_json::to_string_pretty(&entries)? + "\n"
+    } else {
+        entries
+            .iter()
+            .map(|e| format!("{} {} {}\n", e.sha256, e.size, e.path))
+            .collect()
+    };
+    fs::write(manifest, text)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_size_and_digest_of_every_file() {
+        let dir =
+            std::env::temp_dir().join(format!("gdsii_to_gerber-{}-manifest", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (abc, empty) = (dir.join("abc.g"), dir.join("empty.g"));
+        fs::write(&abc, "abc").unwrap();
+        fs::write(&empty, "").unwrap();
+
+        let text = dir.join("m.txt");
+        write(&text, &[&abc, &empty]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&text).unwrap(),
+            format!(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad 3 {}\n\
+                 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 0 {}\n",
+                abc.display(),
+                empty.display()
+            )
+        );
+
+        let json = dir.join("m.json");
+        write(&json, &[&abc]).unwrap();
+        let entries: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        assert_eq!(entries[0]["path"], abc.display().to_string());
+        assert_eq!(entries[0]["size"], 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
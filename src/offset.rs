@@ -0,0 +1,94 @@
+use std::{f64::consts::PI, str::FromStr};
+
+use crate::{
+    union::{cross, dot, normalized, positive_fill, Vec2},
+    Region,
+};
+
+/// How the offset outline is closed around corners that open up a gap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Join {
+    /// Extend both edges until they meet, beveling past a miter length of 4x the offset
+    Miter,
+    /// Sweep an arc around the original corner
+    Round,
+    /// Cut straight across between the two offset edges
+    Bevel,
+}
+
+impl FromStr for Join {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "miter" => Ok(Self::Miter),
+            "round" => Ok(Self::Round),
+            "bevel" => Ok(Self::Bevel),
+            _ => Err(format!(
+                "unknown join style `{s}`, expected miter, round or bevel"
+            )),
+        }
+    }
+}
+
+const MITER_LIMIT: f64 = 4.;
+
+/// Moves every edge of the region outward by `distance` database units (inward when
+/// negative)
+///
+/// Each vertex is moved along its corner first, and the resulting outline is then cut
+/// apart wherever it crosses itself, keeping only the area it still encloses. A region
+/// that collapses comes back empty, and one whose thin parts pinch off comes back as
+/// several regions.
+pub fn offset(region: &Region, distance: f64, join: Join, facets: u32) -> Vec<Region> {
+    let Some(pts) = normalized(region) else {
+        return vec![];
+    };
+    let n = pts.len();
+    let normal = |a: Vec2, b: Vec2| {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let len = dx.hypot(dy);
+        (dy / len, -dx / len)
+    };
+    let mut out: Vec<Vec2> = vec![];
+    for i in 0..n {
+        let (prev, p, next) = (pts[(i + n - 1) % n], pts[i], pts[(i + 1) % n]);
+        let (n1, n2) = (normal(prev, p), normal(p, next));
+        let turn = cross((p.0 - prev.0, p.1 - prev.1), (next.0 - p.0, next.1 - p.1));
+        let denom = 1. + dot(n1, n2);
+        let miter = (
+            p.0 + (n1.0 + n2.0) / denom * distance,
+            p.1 + (n1.1 + n2.1) / denom * distance,
+        );
+        let from = (p.0 + n1.0 * distance, p.1 + n1.1 * distance);
+        let to = (p.0 + n2.0 * distance, p.1 + n2.1 * distance);
+        // Corners turning toward the offset direction open a gap to be joined; the rest
+        // fold inward, where the offset edges simply intersect
+        if turn * distance <= 0. {
+            if denom < 1e-9 {
+                out.extend([from, to]);
+            } else {
+                out.push(miter);
+            }
+            continue;
+        }
+        match join {
+            Join::Miter if 1. / denom.sqrt() * 2f64.sqrt() <= MITER_LIMIT => out.push(miter),
+            Join::Miter | Join::Bevel => out.extend([from, to]),
+            Join::Round => {
+                let start = n1.1.atan2(n1.0);
+                let sweep = cross(n1, n2).atan2(dot(n1, n2));
+                let steps = ((sweep.abs() / (2. * PI) * facets as f64).ceil() as usize).max(1);
+                out.extend((0..=steps).map(|k| {
+                    let a = start + sweep * k as f64 / steps as f64;
+                    (p.0 + a.cos() * distance, p.1 + a.sin() * distance)
+                }));
+            }
+        }
+    }
+    out.push(out[0]);
+    positive_fill(&out.into_iter().collect())
+        .into_iter()
+        .map(|r| r.with_attributes_of(region))
+        .collect()
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use gds21::{GdsElement, GdsLibrary, GdsStruct};
+
+use crate::{info, PatternError, PatternResult};
+
+const BGNSTR: u8 = 0x05;
+const STRNAME: u8 = 0x06;
+const ENDSTR: u8 = 0x07;
+const ENDLIB: u8 = 0x04;
+/// The complete ENDLIB record, which carries no data
+const ENDLIB_RECORD: [u8; 4] = [0, 4, ENDLIB, 0];
+
+/// Loads only the named cells and the cells they place, directly or through other cells,
+/// instead of the whole library
+///
+/// A first pass reads just the record headers and cell names to find where each cell's
+/// records lie in the file. Cells are then read and parsed one at a time as references
+/// reach them, so memory holds the subtree rather than the full library. References to
+/// cells the file does not define are left dangling, as they would be after a full load.
+pub fn load_subtree(path: &Path, cells: &[&str]) -> PatternResult<GdsLibrary> {
+    let invalid = |e: std::io::Error| PatternError::InvalidGds(e.to_string());
+    let mut file = BufReader::new(File::open(path).map_err(invalid)?);
+    let (header, index) = scan(&mut file).map_err(invalid)?;
+    if let Some(missing) = cells.iter().find(|c| !index.contains_key(**c)) {
+        return Err(PatternError::PatternDoesNotExist(missing.to_string()));
+    }
+
+    let mut lib: Option<GdsLibrary> = None;
+    let mut queue: VecDeque<String> = cells.iter().map(|c| c.to_string()).collect();
+    let mut seen: HashSet<String> = queue.iter().cloned().collect();
+    while let Some(name) = queue.pop_front() {
+        let (start, end) = index[&name];
+        let mut bytes = header.clone();
+        file.seek(SeekFrom::Start(start)).map_err(invalid)?;
+        (&mut file)
+            .take(end - start)
+            .read_to_end(&mut bytes)
+            .map_err(invalid)?;
+        bytes.extend(ENDLIB_RECORD);
+        let mut part =
+            GdsLibrary::from_bytes(bytes).map_err(|e| PatternError::InvalidGds(e.to_string()))?;
+        let struc = part.structs.pop().ok_or_else(|| {
+            PatternError::InvalidGds(format!("cell {name} holds no structure records"))
+        })?;
+        for child in children(&struc) {
+            if index.contains_key(child) && seen.insert(child.to_string()) {
+                queue.push_back(child.to_string());
+            }
+        }
+        lib.get_or_insert(part).structs.push(struc);
+    }
+    let lib = lib.unwrap();
+    info!(
+        "Loaded {} of the {} cells in {}",
+        lib.structs.len(),
+        index.len(),
+        path.display()
+    );
+    Ok(lib)
+}
+
+/// Byte range of every cell's records in the file, from its BGNSTR through its ENDSTR
+type Index = HashMap<String, (u64, u64)>;
+
+/// The library records ahead of the first cell, and where each cell lies
+fn scan(file: &mut (impl Read + Seek)) -> std::io::Result<(Vec<u8>, Index)> {
+    let mut header = vec![];
+    let mut index = HashMap::new();
+    let mut offset = 0u64;
+    let mut current: Option<(u64, String)> = None;
+    loop {
+        let mut head = [0u8; 4];
+        file.read_exact(&mut head)?;
+        let len = u16::from_be_bytes([head[0], head[1]]) as u64;
+        if len < 4 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("record at byte {offset} is {len} bytes long"),
+            ));
+        }
+        let body = len - 4;
+        match head[2] {
+            BGNSTR => {
+                current = Some((offset, String::new()));
+                file.seek(SeekFrom::Current(body as i64))?;
+            }
+            STRNAME => {
+                let mut name = vec![0; body as usize];
+                file.read_exact(&mut name)?;
+                while name.last() == Some(&0) {
+                    name.pop();
+                }
+                if let Some((_, current)) = &mut current {
+                    *current = String::from_utf8_lossy(&name).into_owned();
+                }
+            }
+            ENDSTR => {
+                if let Some((start, name)) = current.take() {
+                    index.insert(name, (start, offset + len));
+                }
+                file.seek(SeekFrom::Current(body as i64))?;
+            }
+            ENDLIB => break,
+            _ if index.is_empty() && current.is_none() => {
+                let mut rest = vec![0; body as usize];
+                file.read_exact(&mut rest)?;
+                header.extend(head);
+                header.extend(rest);
+            }
+            _ => {
+                file.seek(SeekFrom::Current(body as i64))?;
+            }
+        }
+        offset += len;
+    }
+    Ok((header, index))
+}
+
+fn children(struc: &GdsStruct) -> impl Iterator<Item = &str> {
+    struc.elems.iter().filter_map(|elem| match elem {
+        GdsElement::GdsStructRef(r) => Some(r.name.as_str()),
+        GdsElement::GdsArrayRef(r) => Some(r.name.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn subtree_matches_the_same_cells_from_a_full_load() {
+        let lib = library([
+            cell("UNUSED", [rect(1, (0, 0), (5, 5))]),
+            cell("LEAF", [rect(1, (0, 0), (10, 10)), rect(2, (0, 0), (3, 3))]),
+            cell(
+                "MID",
+                [aref("LEAF", (2, 2), [(0, 0), (40, 0), (0, 40)], None)],
+            ),
+            cell(
+                "TOP",
+                [
+                    sref("MID", (100, 0), rotated(90.)),
+                    sref("LEAF", (0, 0), None),
+                ],
+            ),
+            cell("OTHER", [sref("UNUSED", (0, 0), None)]),
+        ]);
+        let path =
+            std::env::temp_dir().join(format!("gdsii_to_gerber-{}-stream.gds", std::process::id()));
+        lib.save(&path).unwrap();
+        let full = GdsLibrary::load(&path).unwrap();
+        let subtree = load_subtree(&path, &["TOP"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let names = subtree
+            .structs
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["TOP", "MID", "LEAF"]);
+        for struc in &subtree.structs {
+            assert_eq!(
+                Some(struc),
+                full.structs.iter().find(|s| s.name == struc.name)
+            );
+        }
+        assert_eq!(subtree.units, full.units);
+        for layer in [1, 2] {
+            assert_eq!(
+                sorted_coords(&convert(&subtree, "TOP", layer)),
+                sorted_coords(&convert(&full, "TOP", layer))
+            );
+        }
+    }
+}
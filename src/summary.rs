@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Result;
+use gds21::GdsLibrary;
+use gdsii_to_gerber::Pattern;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct Output {
+    path: String,
+    layer: Option<i16>,
+    regions: usize,
+    datatypes: BTreeMap<i16, usize>,
+    /// Lower-left and upper-right corners in millimeters
+    bbox_mm: Option<[(f64, f64); 2]>,
+    properties: Vec<Property>,
+}
+
+#[derive(Debug, Serialize)]
+struct Property {
+    /// Index of the region within its output, in emission order
+    region: usize,
+    attr: i16,
+    value: String,
+}
+
+/// Describes every output as JSON: its layer, region and datatype counts, extents, and the
+/// GDS properties carried by its regions
+pub fn write<'a>(
+    path: &Path,
+    lib: &GdsLibrary,
+    outputs: impl IntoIterator<Item = (Option<i16>, &'a Path, &'a Pattern)>,
+) -> Result<()> {
+    let outputs: Vec<Output> = outputs
+        .into_iter()
+        .map(|(layer, out, pattern)| Output {
+            path: out.display().to_string(),
+            layer,
+            regions: pattern.len(),
+            datatypes: pattern.datatype_counts(),
+            bbox_mm: pattern
+                .bbox()
+                .map(|(lo, hi)| [lo.as_mm(lib), hi.as_mm(lib)]),
+            properties: pattern
+                .regions()
+                .enumerate()
+                .flat_map(|(region, r)| {
+                    r.properties().iter().map(move |(attr, value)| Property {
+                        region,
+                        attr: *attr,
+                        value: value.clone(),
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    fs::write(path, serde_json::to_string_pretty(&outputs)? + "\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gds21::{GdsBoundary, GdsPoint, GdsProperty, GdsStruct};
+    use gdsii_to_gerber::ConvertOptions;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn lists_the_properties_of_each_region() {
+        let net = GdsProperty {
+            attr: 1,
+            value: "VDD".into(),
+        };
+        let mut top = GdsStruct::new("TOP");
+        for (x, properties) in [(0, vec![]), (100, vec![net])] {
+            top.elems.push(
+                GdsBoundary {
+                    layer: 1,
+                    xy: GdsPoint::vec(&[(x, 0), (x + 10, 0), (x, 10), (x, 0)]),
+                    properties,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+        let mut lib = GdsLibrary::new("lib");
+        lib.structs.push(top);
+        let pattern = Pattern::from_gds_struct(&lib, "TOP", 1, &ConvertOptions::default()).unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "gdsii_to_gerber-{}-summary.json",
+            std::process::id()
+        ));
+        write(&path, &lib, [(Some(1), Path::new("top_1.g"), &pattern)]).unwrap();
+        let summary: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            summary,
+            json!([{
+                "path": "top_1.g",
+                "layer": 1,
+                "regions": 2,
+                "datatypes": { "0": 2 },
+                "bbox_mm": [[0., 0.], [0.00011, 0.00001]],
+                "properties": [{ "region": 1, "attr": 1, "value": "VDD" }],
+            }])
+        );
+    }
+}
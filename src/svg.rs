@@ -0,0 +1,125 @@
+use std::io::{self, Write};
+
+use gds21::GdsLibrary;
+use itertools::Itertools;
+
+use crate::{combined_bbox, mm_from_gds, Pattern};
+
+impl Pattern {
+    /// Writes one SVG `<path>` per region, in millimeters with y pointing down
+    pub fn write_svg(&self, w: &mut impl Write, lib: &GdsLibrary) -> io::Result<()> {
+        for region in self.regions() {
+            let d = region
+                .points()
+                .iter()
+                .map(|p| {
+                    let (x, y) = (mm_from_gds(p.x, lib), -mm_from_gds(p.y, lib));
+                    format!("{} {}", fmt_mm(x), fmt_mm(y))
+                })
+                .join(" L");
+            writeln!(w, r#"<path d="M{d} Z"/>"#)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders several layers into one SVG document, each in its own `<g>` with the given id
+/// and a translucent fill, in the given color or else a distinct generated one
+pub fn write_svg_stack(
+    w: &mut impl Write,
+    layers: &[(String, Option<&str>, &Pattern)],
+    lib: &GdsLibrary,
+) -> io::Result<()> {
+    let (x0, y0, x1, y1) = match combined_bbox(layers.iter().map(|&(_, _, p)| p)) {
+        Some((lo, hi)) => (
+            mm_from_gds(lo.x, lib),
+            -mm_from_gds(hi.y, lib),
+            mm_from_gds(hi.x, lib),
+            -mm_from_gds(lo.y, lib),
+        ),
+        None => Default::default(),
+    };
+    writeln!(
+        w,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">"#,
+        fmt_mm(x0),
+        fmt_mm(y0),
+        fmt_mm(x1 - x0),
+        fmt_mm(y1 - y0)
+    )?;
+    for (i, (id, color, pattern)) in layers.iter().enumerate() {
+        let fill = match color {
+            Some(color) => escape(color),
+            None => {
+                // Golden-angle hue steps keep neighbouring layers far apart on the color wheel
+                let hue = (i as f64 * 137.508) % 360.;
+                format!("hsl({hue:.1}, 70%, 50%)")
+            }
+        };
+        writeln!(
+            w,
+            r#"<g id="{}" fill="{fill}" fill-opacity="0.6">"#,
+            escape(id)
+        )?;
+        pattern.write_svg(w, lib)?;
+        writeln!(w, "</g>")?;
+    }
+    writeln!(w, "</svg>")
+}
+
+/// Escapes text for use inside a double-quoted attribute
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+}
+
+/// Millimeters at the nanometer resolution of the Gerber output, without trailing zeros
+fn fmt_mm(v: f64) -> String {
+    let s = format!("{v:.6}");
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    match s {
+        "-0" => "0".into(),
+        s => s.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn stack_has_one_group_per_layer_with_distinct_fills() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (1000, 1000)),
+                rect(2, (0, 0), (500, 500)),
+                rect(3, (0, 0), (200, 200)),
+            ],
+        )]);
+        let pats = [1, 2, 3].map(|layer| convert(&lib, "TOP", layer));
+        let layers = [
+            ("layer-1".to_string(), None, &pats[0]),
+            ("top \"cu\"".to_string(), Some("#c83"), &pats[1]),
+            ("layer-3".to_string(), None, &pats[2]),
+        ];
+        let mut out = vec![];
+        write_svg_stack(&mut out, &layers, &lib).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        let groups = svg.lines().filter(|l| l.starts_with("<g ")).collect_vec();
+        assert_eq!(
+            groups,
+            [
+                r#"<g id="layer-1" fill="hsl(0.0, 70%, 50%)" fill-opacity="0.6">"#,
+                r##"<g id="top &quot;cu&quot;" fill="#c83" fill-opacity="0.6">"##,
+                r#"<g id="layer-3" fill="hsl(275.0, 70%, 50%)" fill-opacity="0.6">"#,
+            ]
+        );
+        assert_eq!(svg.matches("</g>").count(), 3);
+        assert!(svg.starts_with(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -0.001 0.001 0.001">"#
+        ));
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::{Point, Region};
+
+pub(crate) type Vec2 = (f64, f64);
+
+/// Distance under which a point counts as lying on an edge, in database units
+const EPS: f64 = 1e-6;
+
+/// Merges overlapping regions into the closed contours bounding their union
+///
+/// Outer contours come back counter-clockwise and holes clockwise. Edges of every input
+/// region are split wherever they meet another region, and only the pieces lying on the
+/// boundary of the union are kept and chained back into loops. Only regions whose
+/// bounding boxes meet are compared, so layers of many separate shapes stay fast.
+pub fn union(regions: &[Region]) -> Vec<Region> {
+    let polys = regions.iter().filter_map(normalized).collect_vec();
+    let boxes = polys.iter().map(|poly| bbox(poly)).collect_vec();
+    let neighbors = meeting_boxes(&boxes);
+
+    let mut kept: Vec<(Point, Point)> = vec![];
+    for (i, poly) in polys.iter().enumerate() {
+        for (&a, &b) in poly.iter().circular_tuple_windows() {
+            let along = bbox(&[a, b]);
+            let mut ts = vec![0., 1.];
+            for &j in &neighbors[i] {
+                for (&c, &d) in polys[j].iter().circular_tuple_windows() {
+                    if meet(along, bbox(&[c, d])) {
+                        ts.extend(split_params(a, b, c, d));
+                    }
+                }
+            }
+            ts.sort_by(f64::total_cmp);
+            ts.dedup_by(|x, y| (*x - *y).abs() < 1e-12);
+            for (&t0, &t1) in ts.iter().tuple_windows() {
+                let (p, q) = (lerp(a, b, t0), lerp(a, b, t1));
+                let (p, q) = (Point::from(p), Point::from(q));
+                if p == q {
+                    continue;
+                }
+                let mid = lerp(a, b, (t0 + t1) / 2.);
+                let dir = (b.0 - a.0, b.1 - a.1);
+                let on_boundary = neighbors[i].iter().all(|&j| {
+                    match classify(mid, &polys[j]) {
+                        Side::Outside => true,
+                        Side::Inside => false,
+                        // Shared edges survive once when both sides run the same way, and
+                        // vanish when the regions sit on either side of them
+                        Side::Edge(along) => dot(along, dir) > 0. && i < j,
+                    }
+                });
+                if on_boundary {
+                    kept.push((p, q));
+                }
+            }
+        }
+    }
+    chain(kept)
+}
+
+/// Lower-left and upper-right corners around the points
+type Bbox = (Vec2, Vec2);
+
+fn bbox(pts: &[Vec2]) -> Bbox {
+    pts.iter().fold(
+        ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN)),
+        |(lo, hi), p| {
+            (
+                (lo.0.min(p.0), lo.1.min(p.1)),
+                (hi.0.max(p.0), hi.1.max(p.1)),
+            )
+        },
+    )
+}
+
+/// Whether the boxes overlap or come within [`EPS`] of touching
+fn meet((alo, ahi): Bbox, (blo, bhi): Bbox) -> bool {
+    alo.0 <= bhi.0 + EPS && blo.0 <= ahi.0 + EPS && alo.1 <= bhi.1 + EPS && blo.1 <= ahi.1 + EPS
+}
+
+/// For every box, the other boxes that meet it, found by sweeping across them from left
+/// to right
+fn meeting_boxes(boxes: &[Bbox]) -> Vec<Vec<usize>> {
+    let order = (0..boxes.len())
+        .sorted_by(|&a, &b| boxes[a].0 .0.total_cmp(&boxes[b].0 .0))
+        .collect_vec();
+    let mut neighbors = vec![vec![]; boxes.len()];
+    for (k, &i) in order.iter().enumerate() {
+        for &j in &order[k + 1..] {
+            if boxes[j].0 .0 > boxes[i].1 .0 + EPS {
+                break;
+            }
+            if meet(boxes[i], boxes[j]) {
+                neighbors[i].push(j);
+                neighbors[j].push(i);
+            }
+        }
+    }
+    neighbors
+}
+
+/// Closed contours around the area a possibly self-intersecting region winds around a
+/// positive number of times
+///
+/// An offset outline turns inside out wherever a feature collapses; those loops wind
+/// around zero or negative times and drop away, leaving the parts that survived as
+/// separate contours.
+pub(crate) fn positive_fill(region: &Region) -> Vec<Region> {
+    let mut pts = region
+        .points
+        .iter()
+        .map(|p| (p.x as f64, p.y as f64))
+        .collect_vec();
+    pts.dedup();
+    if pts.len() > 1 && pts.first() == pts.last() {
+        pts.pop();
+    }
+    if pts.len() < 3 {
+        return vec![];
+    }
+    let edges = pts
+        .iter()
+        .circular_tuple_windows()
+        .map(|(&a, &b)| (a, b))
+        .collect_vec();
+    let mut kept: Vec<(Point, Point)> = vec![];
+    for (i, &(a, b)) in edges.iter().enumerate() {
+        let mut ts = vec![0., 1.];
+        for (j, &(c, d)) in edges.iter().enumerate() {
+            if i != j {
+                ts.extend(split_params(a, b, c, d));
+            }
+        }
+        ts.sort_by(f64::total_cmp);
+        ts.dedup_by(|x, y| (*x - *y).abs() < 1e-12);
+        for (&t0, &t1) in ts.iter().tuple_windows() {
+            let (p, q) = (Point::from(lerp(a, b, t0)), Point::from(lerp(a, b, t1)));
+            if p == q {
+                continue;
+            }
+            let mid = lerp(a, b, (t0 + t1) / 2.);
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let step = SIDE_STEP / dx.hypot(dy);
+            let left = (mid.0 - dy * step, mid.1 + dx * step);
+            let right = (mid.0 + dy * step, mid.1 - dx * step);
+            if winding(left, &pts) > 0 && winding(right, &pts) <= 0 {
+                kept.push((p, q));
+            }
+        }
+    }
+    chain(kept)
+}
+
+/// How far either side of an edge its winding numbers are sampled, in database units
+const SIDE_STEP: f64 = 1e-3;
+
+/// Signed number of times the closed ring `poly` goes counter-clockwise around `p`
+fn winding(p: Vec2, poly: &[Vec2]) -> i32 {
+    poly.iter()
+        .circular_tuple_windows()
+        .map(|(&a, &b)| {
+            let side = cross((b.0 - a.0, b.1 - a.1), (p.0 - a.0, p.1 - a.1));
+            match (a.1 <= p.1, b.1 <= p.1) {
+                (true, false) if side > 0. => 1,
+                (false, true) if side < 0. => -1,
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+/// Drops the closing point and degenerate spans, and orients the region counter-clockwise
+pub(crate) fn normalized(region: &Region) -> Option<Vec<Vec2>> {
+    let mut pts = region
+        .points
+        .iter()
+        .map(|p| (p.x as f64, p.y as f64))
+        .collect_vec();
+    pts.dedup();
+    if pts.len() > 1 && pts.first() == pts.last() {
+        pts.pop();
+    }
+    if pts.len() < 3 {
+        return None;
+    }
+    let twice_area: f64 = pts
+        .iter()
+        .circular_tuple_windows()
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum();
+    if twice_area == 0. {
+        return None;
+    }
+    if twice_area < 0. {
+        pts.reverse();
+    }
+    Some(pts)
+}
+
+fn lerp(a: Vec2, b: Vec2, t: f64) -> Vec2 {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
+
+pub(crate) fn dot(a: Vec2, b: Vec2) -> f64 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+pub(crate) fn cross(a: Vec2, b: Vec2) -> f64 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+/// Parameters along `a`-`b` at which segment `c`-`d` crosses or starts/stops overlapping it
+fn split_params(a: Vec2, b: Vec2, c: Vec2, d: Vec2) -> Vec<f64> {
+    let r = (b.0 - a.0, b.1 - a.1);
+    let s = (d.0 - c.0, d.1 - c.1);
+    let ac = (c.0 - a.0, c.1 - a.1);
+    let denom = cross(r, s);
+    let inside = |t: f64| t > 0. && t < 1.;
+    if denom.abs() > f64::EPSILON * dot(r, r).max(dot(s, s)) {
+        let t = cross(ac, s) / denom;
+        let u = cross(ac, r) / denom;
+        if inside(t) && (0. ..=1.).contains(&u) {
+            return vec![t];
+        }
+        return vec![];
+    }
+    // Parallel: only collinear overlaps split the edge, at the other segment's ends
+    if cross(ac, r).abs() > EPS * dot(r, r).sqrt() {
+        return vec![];
+    }
+    let rr = dot(r, r);
+    [c, d]
+        .into_iter()
+        .map(|p| dot((p.0 - a.0, p.1 - a.1), r) / rr)
+        .filter(|&t| inside(t))
+        .collect()
+}
+
+/// Whether every vertex of `inner` lies inside or on the boundary of `outer`
+pub(crate) fn contains(outer: &Region, inner: &Region) -> bool {
+    let Some(poly) = normalized(outer) else {
+        return false;
+    };
+    inner
+        .points
+        .iter()
+        .all(|p| !matches!(classify((p.x as f64, p.y as f64), &poly), Side::Outside))
+}
+
+enum Side {
+    Inside,
+    Outside,
+    /// On the boundary, along an edge running in the given direction
+    Edge(Vec2),
+}
+
+fn classify(p: Vec2, poly: &[Vec2]) -> Side {
+    let mut inside = false;
+    for (&a, &b) in poly.iter().circular_tuple_windows() {
+        let e = (b.0 - a.0, b.1 - a.1);
+        let ap = (p.0 - a.0, p.1 - a.1);
+        let len2 = dot(e, e);
+        let t = dot(ap, e) / len2;
+        if (0. ..=1.).contains(&t) && cross(e, ap).abs() <= EPS * len2.sqrt() {
+            return Side::Edge(e);
+        }
+        if (a.1 > p.1) != (b.1 > p.1) && p.0 < a.0 + (p.1 - a.1) * e.0 / e.1 {
+            inside = !inside;
+        }
+    }
+    if inside {
+        Side::Inside
+    } else {
+        Side::Outside
+    }
+}
+
+/// Links directed segments end to start into closed regions, turning as far left as
+/// possible wherever several contours touch so that they stay separate
+fn chain(segments: Vec<(Point, Point)>) -> Vec<Region> {
+    let mut outgoing: HashMap<Point, Vec<usize>> = HashMap::new();
+    for (k, (p, _)) in segments.iter().enumerate() {
+        outgoing.entry(*p).or_default().push(k);
+    }
+    let mut used = vec![false; segments.len()];
+    let mut contours = vec![];
+    for start in 0..segments.len() {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        let mut contour = vec![segments[start].0];
+        let mut current = start;
+        loop {
+            let (p, q) = segments[current];
+            contour.push(q);
+            if q == contour[0] {
+                break;
+            }
+            let back = ((p.x - q.x) as f64, (p.y - q.y) as f64);
+            // Angle swept counter-clockwise from the reversed incoming edge
+            let turn = |k: usize| {
+                let (s, e) = segments[k];
+                let out = ((e.x - s.x) as f64, (e.y - s.y) as f64);
+                let angle = cross(back, out).atan2(dot(back, out));
+                if angle <= 0. {
+                    angle + std::f64::consts::TAU
+                } else {
+                    angle
+                }
+            };
+            let next = outgoing
+                .get(&q)
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|&k| !used[k])
+                .max_by(|&k1, &k2| turn(k1).total_cmp(&turn(k2)));
+            match next {
+                Some(k) => {
+                    used[k] = true;
+                    current = k;
+                }
+                None => break,
+            }
+        }
+        if contour.len() > 3 && contour.first() == contour.last() {
+            contours.push(contour.into_iter().collect());
+        }
+    }
+    contours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: i32, y: i32, side: i32) -> Region {
+        Region::rect((
+            Point { x, y },
+            Point {
+                x: x + side,
+                y: y + side,
+            },
+        ))
+    }
+
+    #[test]
+    fn overlapping_regions_merge_into_one_contour() {
+        let merged = union(&[square(0, 0, 10), square(5, 5, 10), square(14, 0, 6)]);
+        let [region] = &merged[..] else {
+            panic!("{merged:?}")
+        };
+        assert_eq!(region.signed_area(), 100. + 100. - 25. + 36. - 1.);
+    }
+
+    #[test]
+    fn regions_sharing_an_edge_merge() {
+        let merged = union(&[square(0, 0, 10), square(10, 0, 10)]);
+        let [region] = &merged[..] else {
+            panic!("{merged:?}")
+        };
+        assert_eq!(region.signed_area(), 200.);
+    }
+
+    #[test]
+    fn separate_regions_stay_apart() {
+        let grid = (0..30)
+            .flat_map(|i| (0..30).map(move |j| square(i * 20, j * 20, 10)))
+            .collect_vec();
+        let merged = union(&grid);
+        assert_eq!(merged.len(), 900);
+        assert!(merged.iter().all(|r| r.signed_area() == 100.));
+    }
+
+    #[test]
+    fn enclosed_gaps_come_back_as_clockwise_holes() {
+        let frame = [
+            Region::rect((Point { x: 0, y: 0 }, Point { x: 30, y: 10 })),
+            Region::rect((Point { x: 0, y: 20 }, Point { x: 30, y: 30 })),
+            Region::rect((Point { x: 0, y: 0 }, Point { x: 10, y: 30 })),
+            Region::rect((Point { x: 20, y: 0 }, Point { x: 30, y: 30 })),
+        ];
+        let areas = union(&frame)
+            .iter()
+            .map(Region::signed_area)
+            .sorted_by(f64::total_cmp)
+            .collect_vec();
+        assert_eq!(areas, [-100., 900.]);
+    }
+}
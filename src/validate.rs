@@ -0,0 +1,230 @@
+use gds21::{GdsElement, GdsLibrary, GdsStruct};
+use gerber_types::CoordinateFormat;
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    bounds, cell_layers, find_struct, CellIndex, ConvertOptions, Pattern, PatternError,
+    PatternResult, Point, Region, Walk,
+};
+
+/// Something in a cell hierarchy that would make conversion fail or come out wrong
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Problem {
+    #[error("Cell {cell} references {missing}, which is not in the library")]
+    MissingRef { cell: String, missing: String },
+    #[error("Cell {} places itself through {}", .cycle[0], .cycle.join(" -> "))]
+    CyclicRef { cycle: Vec<String> },
+    #[error("Cell {cell} has a {kind} on layer {layer}, which cannot be converted")]
+    Unsupported {
+        cell: String,
+        kind: &'static str,
+        layer: i16,
+    },
+    #[error("Cell {cell} has a boundary on layer {layer} with only {points} distinct points")]
+    TooFewPoints {
+        cell: String,
+        layer: i16,
+        points: usize,
+    },
+    #[error("Cell {cell} has a boundary on layer {layer} enclosing no area")]
+    ZeroArea { cell: String, layer: i16 },
+    #[error(
+        "Cell {cell} has a boundary on layer {layer} whose last point does not repeat its first"
+    )]
+    Unclosed { cell: String, layer: i16 },
+    #[error(
+        "Cell {cell} flattens to coordinates reaching {extent} mm, beyond the {format} coordinate format"
+    )]
+    OutOfRange {
+        cell: String,
+        extent: f64,
+        format: String,
+    },
+}
+
+/// Checks the named cell and everything it places for problems, without converting any
+/// geometry
+///
+/// Every cell is reported on at most once, in the order the hierarchy is first walked.
+/// Coordinates are checked against `format` where they land once the named cell is
+/// flattened, since placements can move geometry that fits in its own cell out of range.
+pub fn validate(
+    lib: &GdsLibrary,
+    name: &str,
+    format: CoordinateFormat,
+) -> PatternResult<Vec<Problem>> {
+    let mut problems = vec![];
+    let mut done = vec![];
+    let mut stack = vec![];
+    walk(
+        lib,
+        find_struct(lib, name)?,
+        &mut stack,
+        &mut done,
+        &mut problems,
+    );
+    if let Some(bbox) = flat_extent(lib, name)? {
+        let outline: Pattern = [Region::rect(bbox)].into_iter().collect();
+        if let Err(PatternError::CoordinateOverflow { extent, format, .. }) =
+            outline.check_fits(lib, format)
+        {
+            problems.push(Problem::OutOfRange {
+                cell: name.to_string(),
+                extent,
+                format,
+            });
+        }
+    }
+    Ok(problems)
+}
+
+/// Bounding box of the named cell flattened over every layer it draws on
+///
+/// Layers that cannot be flattened, such as those behind a cycle, are left out, as the
+/// hierarchy walk reports why.
+fn flat_extent(lib: &GdsLibrary, name: &str) -> PatternResult<Option<(Point, Point)>> {
+    let index = CellIndex::new(lib);
+    let opts = ConvertOptions {
+        allow_missing_refs: true,
+        ..Default::default()
+    };
+    let mut corners = vec![];
+    for layer in cell_layers(lib, name)? {
+        let mut walk = Walk::new(&index);
+        if let Ok(Some((lo, hi))) = Pattern::extent(lib, name, layer, &opts, &mut walk) {
+            corners.extend([lo, hi]);
+        }
+    }
+    Ok(bounds(corners))
+}
+
+fn walk<'a>(
+    lib: &'a GdsLibrary,
+    struc: &'a GdsStruct,
+    stack: &mut Vec<&'a str>,
+    done: &mut Vec<&'a str>,
+    problems: &mut Vec<Problem>,
+) {
+    if let Some(start) = stack.iter().position(|&s| s == struc.name) {
+        let cycle = stack[start..]
+            .iter()
+            .chain([&struc.name.as_str()])
+            .map(|s| s.to_string())
+            .collect();
+        problems.push(Problem::CyclicRef { cycle });
+        return;
+    }
+    if done.contains(&struc.name.as_str()) {
+        return;
+    }
+    stack.push(&struc.name);
+    check_cell(struc, problems);
+    for elem in &struc.elems {
+        let child = match elem {
+            GdsElement::GdsStructRef(r) => &r.name,
+            GdsElement::GdsArrayRef(r) => &r.name,
+            _ => continue,
+        };
+        match find_struct(lib, child) {
+            Ok(child) => walk(lib, child, stack, done, problems),
+            Err(_) => problems.push(Problem::MissingRef {
+                cell: struc.name.clone(),
+                missing: child.clone(),
+            }),
+        }
+    }
+    stack.pop();
+    done.push(&struc.name);
+}
+
+/// Problems with the elements placed directly in one cell
+fn check_cell(struc: &GdsStruct, problems: &mut Vec<Problem>) {
+    let cell = || struc.name.clone();
+    for elem in &struc.elems {
+        match elem {
+            GdsElement::GdsBoundary(b) => {
+                let layer = b.layer;
+                if b.xy.first() != b.xy.last() {
+                    problems.push(Problem::Unclosed {
+                        cell: cell(),
+                        layer,
+                    });
+                }
+                let distinct = b.xy.iter().map(|p| (p.x, p.y)).unique().count();
+                if distinct < 3 {
+                    problems.push(Problem::TooFewPoints {
+                        cell: cell(),
+                        layer,
+                        points: distinct,
+                    });
+                } else if b.xy.iter().collect::<Region>().signed_area() == 0. {
+                    problems.push(Problem::ZeroArea {
+                        cell: cell(),
+                        layer,
+                    });
+                }
+            }
+            GdsElement::GdsNode(n) => problems.push(Problem::Unsupported {
+                cell: cell(),
+                kind: "node",
+                layer: n.layer,
+            }),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn reports_a_dangling_reference_and_a_degenerate_boundary() {
+        let lib = library([cell(
+            "TOP",
+            [
+                rect(1, (0, 0), (10, 10)),
+                boundary(2, 0, &[(0, 0), (5, 5), (10, 10)]),
+                sref("GONE", (0, 0), None),
+            ],
+        )]);
+        let problems = validate(&lib, "TOP", CoordinateFormat::new(6, 6)).unwrap();
+        assert_eq!(
+            problems,
+            [
+                Problem::ZeroArea {
+                    cell: "TOP".into(),
+                    layer: 2
+                },
+                Problem::MissingRef {
+                    cell: "TOP".into(),
+                    missing: "GONE".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_the_range_where_placements_put_the_geometry() {
+        let lib = library([
+            cell("LEAF", [rect(1, (0, 0), (10, 10))]),
+            cell("TOP", [sref("LEAF", (150_000_000, 0), None)]),
+        ]);
+        let format = CoordinateFormat::new(2, 6);
+        assert_eq!(validate(&lib, "LEAF", format).unwrap(), []);
+        assert_eq!(
+            validate(&lib, "TOP", format).unwrap(),
+            [Problem::OutOfRange {
+                cell: "TOP".into(),
+                extent: 150.00001,
+                format: "2.6".into()
+            }]
+        );
+        assert_eq!(
+            validate(&lib, "TOP", CoordinateFormat::new(3, 6)).unwrap(),
+            []
+        );
+    }
+}
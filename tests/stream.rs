@@ -0,0 +1,98 @@
+//! Peak memory of the streaming loader against a full load, measured by counting every
+//! allocation the test process makes
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use gds21::{GdsBoundary, GdsLibrary, GdsPoint, GdsStruct, GdsStructRef};
+use gdsii_to_gerber::{load_subtree, ConvertOptions, GerberOptions, Pattern};
+
+struct Tracking;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Tracking = Tracking;
+
+/// Bytes allocated at the busiest point of `f`, above what was live when it started
+fn peak_of<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(start, Ordering::Relaxed);
+    let value = f();
+    (value, PEAK.load(Ordering::Relaxed) - start)
+}
+
+fn square(x: i32, y: i32) -> GdsBoundary {
+    GdsBoundary {
+        layer: 1,
+        xy: GdsPoint::vec(&[(x, y), (x + 10, y), (x + 10, y + 10), (x, y + 10), (x, y)]),
+        ..Default::default()
+    }
+}
+
+/// Gerber of layer 1 of the cell, as the command line would write it
+fn gerber(lib: &GdsLibrary, cell: &str) -> Vec<u8> {
+    let pat = Pattern::from_gds_struct(lib, cell, 1, &ConvertOptions::default()).unwrap();
+    let mut out = vec![];
+    pat.write_gerber(&mut out, lib, &GerberOptions::default())
+        .unwrap();
+    out
+}
+
+#[test]
+fn streaming_a_small_subtree_peaks_below_a_full_load() {
+    // A small top cell in a library dominated by a thousand cells it never places
+    let mut lib = GdsLibrary::new("synthetic");
+    for i in 0..1000 {
+        let mut fill = GdsStruct::new(format!("FILL{i}"));
+        fill.elems
+            .extend((0..50).map(|j| square(j * 20, i * 20).into()));
+        lib.structs.push(fill);
+    }
+    let mut leaf = GdsStruct::new("LEAF");
+    leaf.elems.push(square(0, 0).into());
+    let mut top = GdsStruct::new("TOP");
+    top.elems.extend((0..4).map(|k| {
+        GdsStructRef {
+            name: "LEAF".into(),
+            xy: GdsPoint::new(k * 100, 0),
+            ..Default::default()
+        }
+        .into()
+    }));
+    lib.structs.extend([leaf, top]);
+    let path = std::env::temp_dir().join(format!("gdsii_to_gerber-{}-big.gds", std::process::id()));
+    lib.save(&path).unwrap();
+    drop(lib);
+
+    let (full, full_peak) = peak_of(|| GdsLibrary::load(&path).unwrap());
+    let expected = gerber(&full, "TOP");
+    drop(full);
+    let (subtree, subtree_peak) = peak_of(|| load_subtree(&path, &["TOP"]).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(gerber(&subtree, "TOP"), expected);
+    assert_eq!(subtree.structs.len(), 2);
+    assert!(
+        subtree_peak * 10 < full_peak,
+        "streaming peaked at {subtree_peak} bytes against {full_peak} for a full load"
+    );
+}